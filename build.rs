@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    // Values are carried as `bytes::Bytes`, cheap to clone out of the cache
+    tonic_prost_build::configure()
+        .bytes(".")
+        .compile_protos(&["proto/cache.proto"], &["proto"])?;
+    Ok(())
+}
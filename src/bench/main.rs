@@ -0,0 +1,42 @@
+use std::fs;
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use grpc_cache::workload::{self, BenchArgs, BenchConfig, Error};
+
+/// Benchmarks a cache backend and prints a JSON summary.
+#[derive(Parser, Debug)]
+struct Args {
+    /// TOML file with benchmark parameters, flags take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Write the JSON summary to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    bench: BenchArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+        None => BenchConfig::default(),
+    };
+    args.bench.apply(&mut config);
+    config.validate()?;
+
+    let summary = workload::run(config).await?;
+    let json = serde_json::to_string_pretty(&summary)?;
+    match args.output {
+        Some(path) => fs::write(path, json + "\n")?,
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
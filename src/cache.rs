@@ -0,0 +1,223 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::error::Result;
+
+/// Size and readers of one of the maps behind a cache.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapStatus {
+    pub items: usize,
+    /// `DashMap` shards, 0 for other maps.
+    pub shards: usize,
+    /// Readers currently holding the map, when the backend can tell.
+    pub readers: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStatus {
+    /// `[green, blue]` for green/blue caches, a single map otherwise.
+    pub maps: Vec<MapStatus>,
+    /// Writes not yet visible to readers.
+    pub pending: usize,
+    /// Index in `maps` readers are served from.
+    pub current: usize,
+    /// Number of times pending writes were published.
+    pub generation: u64,
+    pub last_flush: Option<SystemTime>,
+}
+
+impl CacheStatus {
+    /// Items visible to readers.
+    pub fn items(&self) -> usize {
+        self.maps.get(self.current).map_or(0, |m| m.items)
+    }
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: &[&str] = if self.maps.len() == 2 {
+            &["Green", "Blue"]
+        } else {
+            &["Cache"]
+        };
+        write!(f, "************ ")?;
+        for (name, m) in names.iter().zip(&self.maps) {
+            write!(
+                f,
+                "{}: {}_items {}_shards {}_readers // ",
+                name, m.items, m.shards, m.readers
+            )?;
+        }
+        write!(
+            f,
+            "Pending: {} Current: {} Generation: {}",
+            self.pending, self.current, self.generation
+        )
+    }
+}
+
+/// Writes made visible by one flush, sent to the subscribers of a cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Published<K, V> {
+    pub generation: u64,
+    /// In write order, `None` is a removed key.
+    pub changes: Vec<(K, Option<V>)>,
+    /// Every item was replaced by a bulk load, `changes` is empty.
+    pub replaced: bool,
+}
+
+/// Read side of a cache backend.
+pub trait CacheReader<K, V> {
+    /// Looks up a batch of keys, `result[i]` is the value stored for the
+    /// i-th key. Keys can be any borrowed form of `K`, e.g. `&str` for `String`
+    /// keys, so callers can look up straight from a request buffer.
+    fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>;
+
+    /// Calls `f` with every key of the batch and its value, borrowed from the
+    /// cache instead of cloned, e.g. to serialize it straight into a response.
+    /// The batch is read from one generation like `get`, and `f` should not
+    /// block: a flush may wait for the batch to end.
+    fn get_with<'q, Q, I, F>(&self, keys: I, mut f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        let keys: Vec<&Q> = keys.into_iter().collect();
+        for (k, v) in keys.iter().zip(self.get(keys.iter().copied())) {
+            f(k, v.as_ref());
+        }
+    }
+}
+
+/// Write side of a cache backend.
+///
+/// Writes are only guaranteed to be visible to readers once `flush` returns.
+pub trait CacheWriter<K, V> {
+    fn put(&self, key: K, value: V) -> Result<()>;
+
+    /// Publishes the pending writes, returns the generation now visible.
+    fn flush(&self) -> Result<u64>;
+
+    fn status(&self) -> CacheStatus;
+}
+
+/// Shared caches can be handed to readers and writers as `Arc`s.
+impl<K, V, T: CacheReader<K, V> + ?Sized> CacheReader<K, V> for Arc<T> {
+    fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        T::get(self, keys)
+    }
+
+    fn get_with<'q, Q, I, F>(&self, keys: I, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        T::get_with(self, keys, f)
+    }
+}
+
+impl<K, V, T: CacheWriter<K, V> + ?Sized> CacheWriter<K, V> for Arc<T> {
+    fn put(&self, key: K, value: V) -> Result<()> {
+        T::put(self, key, value)
+    }
+
+    fn flush(&self) -> Result<u64> {
+        T::flush(self)
+    }
+
+    fn status(&self) -> CacheStatus {
+        T::status(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gbcache, gbcache2, lrcache, rwcache};
+
+    fn write_flush_read<W, R>(w: &W, r: &R)
+    where
+        W: CacheWriter<i32, i32>,
+        R: CacheReader<i32, i32>,
+    {
+        assert_eq!(w.put(1, 100), Ok(()));
+        assert_eq!(w.put(2, 200), Ok(()));
+        assert_eq!(w.flush(), Ok(1));
+        assert_eq!(vec![Some(100), Some(200), None], r.get(&[1, 2, 3]));
+
+        assert_eq!(w.put(1, 1000), Ok(()));
+        assert_eq!(w.flush(), Ok(2));
+        assert_eq!(vec![Some(1000), Some(200)], r.get(&[1, 2]));
+
+        let mut visited = Vec::new();
+        r.get_with(&[2, 3, 1], |k, v| visited.push((*k, v.copied())));
+        assert_eq!(visited, vec![(2, Some(200)), (3, None), (1, Some(1000))]);
+
+        let status = w.status();
+        assert_eq!(status.items(), 2);
+        assert_eq!(status.pending, 0);
+        assert_eq!(status.generation, 2);
+    }
+
+    /// `String` keys looked up as `&str` slices of a request buffer.
+    fn borrowed_read<W, R>(w: &W, r: &R)
+    where
+        W: CacheWriter<String, i32>,
+        R: CacheReader<String, i32>,
+    {
+        assert_eq!(w.put("a".to_string(), 1), Ok(()));
+        assert_eq!(w.flush(), Ok(1));
+        let request = "a,b";
+        assert_eq!(vec![Some(1), None], r.get(request.split(',')));
+
+        let mut visited = Vec::new();
+        r.get_with(["b", "a"], |k, v| visited.push((k.to_string(), v.copied())));
+        assert_eq!(visited, vec![("b".to_string(), None), ("a".to_string(), Some(1))]);
+    }
+
+    #[test]
+    fn test_backends() {
+        let cache = gbcache::GreenBlueCache::with_capacity(16);
+        write_flush_read(&cache, &cache);
+
+        let cache = gbcache2::GreenBlueCache::default();
+        write_flush_read(&cache, &cache);
+
+        let cache = rwcache::RwCache::default();
+        write_flush_read(&cache, &cache);
+
+        let (w, r) = lrcache::new();
+        write_flush_read(&w, &r);
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let cache = gbcache::GreenBlueCache::with_capacity(16);
+        borrowed_read(&cache, &cache);
+
+        let cache = gbcache2::GreenBlueCache::default();
+        borrowed_read(&cache, &cache);
+
+        let cache = rwcache::RwCache::default();
+        borrowed_read(&cache, &cache);
+
+        let (w, r) = lrcache::new();
+        borrowed_read(&w, &r);
+    }
+}
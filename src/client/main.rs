@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use bytes::Bytes;
+use clap::Parser;
+use tonic::transport::{Channel, Endpoint};
+
+use grpc_cache::workload::{self, BenchArgs, BenchConfig, BenchReader, BenchWriter, Error, Result};
+use grpc_cache::{CacheStatus, MapStatus};
+
+pub mod pb {
+    tonic::include_proto!("cache");
+}
+
+use pb::cache_client::CacheClient;
+use pb::*;
+
+/// Runs the `cache-bench` workload against a cache-server over gRPC.
+///
+/// `--backend` is ignored, the server decides which cache is used.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Server address
+    #[arg(long, default_value = "http://127.0.0.1:50051")]
+    addr: String,
+
+    /// Table the workload runs against, the default table if empty
+    #[arg(long, default_value = "")]
+    table: String,
+
+    /// Connections to the server, readers are spread over them
+    #[arg(long, default_value_t = 1)]
+    channels: usize,
+
+    /// TOML file with benchmark parameters, flags take precedence
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Write the JSON summary to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    bench: BenchArgs,
+}
+
+/// One gRPC client, each reader task gets its own so up to `readers`
+/// requests are in flight over the channels.
+#[derive(Clone)]
+struct Remote {
+    client: CacheClient<Channel>,
+    table: String,
+}
+
+impl BenchReader for Remote {
+    async fn get(&mut self, keys: &[String]) -> Result<Vec<Option<Bytes>>> {
+        let request = BatchGetRequest {
+            keys: keys.to_vec(),
+            min_generation: 0,
+            table: self.table.clone(),
+        };
+        let response = self.client.batch_get(request).await?.into_inner();
+        Ok(response.values.into_iter().map(|v| v.value).collect())
+    }
+}
+
+impl BenchWriter for Remote {
+    async fn put(&self, key: String, value: Bytes) -> Result<()> {
+        let request = PutRequest {
+            key,
+            value,
+            ttl_ms: 0,
+            table: self.table.clone(),
+        };
+        self.client.clone().put(request).await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let request = FlushRequest {
+            table: self.table.clone(),
+        };
+        self.client.clone().flush(request).await?;
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<CacheStatus> {
+        let request = StatusRequest {
+            table: self.table.clone(),
+        };
+        let status = self.client.clone().status(request).await?.into_inner();
+        Ok(CacheStatus {
+            maps: status
+                .maps
+                .iter()
+                .map(|m| MapStatus {
+                    items: m.items as usize,
+                    shards: m.shards as usize,
+                    readers: m.readers as usize,
+                })
+                .collect(),
+            pending: status.pending as usize,
+            current: status.current as usize,
+            generation: status.generation,
+            last_flush: (status.last_flush_ms > 0)
+                .then(|| UNIX_EPOCH + Duration::from_millis(status.last_flush_ms)),
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::result::Result<(), Error> {
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+        None => BenchConfig::default(),
+    };
+    args.bench.apply(&mut config);
+    config.validate()?;
+
+    let endpoint = Endpoint::from_shared(args.addr)?;
+    let mut channels = Vec::with_capacity(args.channels.max(1));
+    for _ in 0..args.channels.max(1) {
+        channels.push(endpoint.connect().await?);
+    }
+
+    let remote = |channel: &Channel| Remote {
+        client: CacheClient::new(channel.clone()),
+        table: args.table.clone(),
+    };
+    let writer = remote(&channels[0]);
+    let reader = |i: usize| remote(&channels[i % channels.len()]);
+    let summary = workload::run_with(writer, reader, config).await?;
+
+    let json = serde_json::to_string_pretty(&summary)?;
+    match args.output {
+        Some(path) => fs::write(path, json + "\n")?,
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+pub type Result<T> = std::result::Result<T, CacheError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheError {
+    NotFound,
+    CannotSwitch,
+    CannotWrite,
+    /// A bulk load did not receive the entries it was told to expect.
+    LoadMismatch,
+    /// A read asked for a generation that is not published yet.
+    GenerationNotReached,
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(
+        &self,
+        formatter: &mut std::fmt::Formatter<'_>,
+    ) -> std::result::Result<(), std::fmt::Error> {
+        formatter.write_str(&format!("{:?}", self))?;
+        Ok(())
+    }
+}
+
+impl std::error::Error for CacheError {}
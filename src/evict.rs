@@ -0,0 +1,466 @@
+/// Memory budget and eviction
+///
+/// A cache with a budget weighs every item with a `Weigher` and keeps the
+/// items it holds once the pending writes are flushed within the budget. A
+/// write that would exceed it either fails with `CannotWrite` or evicts other
+/// items, picked by the `Policy`. Evictions are queued in the pending log as
+/// removals, so the flush publishing the write publishes them too and both
+/// maps of a green/blue cache apply them in the same order. Each item is
+/// weighed once, the two maps together hold about twice the budget.
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use clap::ValueEnum;
+use parking_lot::{Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CacheError, Result};
+
+/// Reads remembered between two writes, later ones are dropped rather than
+/// making readers wait.
+const READ_BUFFER: usize = 4096;
+
+/// Read buffers, each thread records its reads in one of them so readers
+/// rarely meet on a lock.
+const READ_STRIPES: usize = 16;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Read buffer of the current thread, assigned round robin.
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % READ_STRIPES;
+}
+
+/// Read buffer on its own cache line.
+#[repr(align(64))]
+#[derive(Default)]
+struct ReadStripe(Mutex<Vec<u64>>);
+
+/// Rows of the frequency sketch.
+const SKETCH_DEPTH: usize = 4;
+
+/// Counts of the frequency sketch saturate at this value.
+const SKETCH_MAX: u8 = 15;
+
+/// Size of an item in bytes, as accounted against the budget.
+pub trait Weigher<K, V>: Send + Sync {
+    fn weigh(&self, key: &K, value: &V) -> usize;
+}
+
+impl<K, V, F> Weigher<K, V> for F
+where
+    F: Fn(&K, &V) -> usize + Send + Sync,
+{
+    fn weigh(&self, key: &K, value: &V) -> usize {
+        self(key, value)
+    }
+}
+
+/// What a write over the memory budget does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Nothing is evicted, the write fails with `CannotWrite`.
+    Reject,
+    /// Evicts the items read or written least recently.
+    #[default]
+    Lru,
+    /// Evicts like `Lru`, but only admits a new key used at least as often
+    /// lately as the items it would evict (TinyLFU), the write fails with
+    /// `CannotWrite` otherwise.
+    Lfu,
+    /// Evicts the items written first, reads do not count.
+    Fifo,
+}
+
+impl Policy {
+    fn tracks_reads(self) -> bool {
+        matches!(self, Policy::Lru | Policy::Lfu)
+    }
+}
+
+struct Item {
+    weight: usize,
+    tick: u64,
+    hash: u64,
+}
+
+/// Weight and eviction order of the items of a cache.
+pub(crate) struct Tracker<K> {
+    weight: usize,
+    tick: u64,
+    items: HashMap<K, Item>,
+    /// Keys by last use, or by first write for FIFO, oldest first.
+    order: BTreeMap<u64, K>,
+    /// Tick of the key with each hash, reads only record the hash.
+    ticks: HashMap<u64, u64>,
+    sketch: Option<Sketch>,
+}
+
+impl<K: Eq + Hash + Clone> Tracker<K> {
+    fn new(policy: Policy, width: usize) -> Self {
+        Self {
+            weight: 0,
+            tick: 0,
+            items: HashMap::new(),
+            order: BTreeMap::new(),
+            ticks: HashMap::new(),
+            sketch: (policy == Policy::Lfu).then(|| Sketch::new(width)),
+        }
+    }
+
+    fn set(&mut self, key: &K, hash: u64, weight: usize, policy: Policy) {
+        if let Some(item) = self.items.get_mut(key) {
+            self.weight = self.weight - item.weight + weight;
+            item.weight = weight;
+            if policy != Policy::Fifo {
+                self.touch(hash);
+            }
+            return;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        self.weight += weight;
+        self.items.insert(key.clone(), Item { weight, tick, hash });
+        self.order.insert(tick, key.clone());
+        self.ticks.insert(hash, tick);
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(item) = self.items.remove(key) {
+            self.weight -= item.weight;
+            self.order.remove(&item.tick);
+            if self.ticks.get(&item.hash) == Some(&item.tick) {
+                self.ticks.remove(&item.hash);
+            }
+        }
+    }
+
+    /// Moves the key with `hash` to the back of the eviction order. Keys
+    /// sharing a hash only keep the order of the last one written.
+    fn touch(&mut self, hash: u64) {
+        let Some(tick) = self.ticks.get(&hash).copied() else {
+            return;
+        };
+        let Some(key) = self.order.remove(&tick) else {
+            return;
+        };
+        self.tick += 1;
+        if let Some(item) = self.items.get_mut(&key) {
+            item.tick = self.tick;
+        }
+        self.order.insert(self.tick, key);
+        self.ticks.insert(hash, self.tick);
+    }
+}
+
+/// Count-min sketch of how often keys were used lately, counts are halved
+/// every `10 * width` uses so old popularity fades.
+struct Sketch {
+    counters: Vec<u8>,
+    width: usize,
+    additions: usize,
+}
+
+impl Sketch {
+    fn new(width: usize) -> Self {
+        let width = width.max(64).next_power_of_two();
+        Self {
+            counters: vec![0; width * SKETCH_DEPTH],
+            width,
+            additions: 0,
+        }
+    }
+
+    fn index(&self, hash: u64, row: usize) -> usize {
+        let h = hash
+            .rotate_left(row as u32 * 16)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        row * self.width + ((h >> 32) as usize & (self.width - 1))
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for row in 0..SKETCH_DEPTH {
+            let i = self.index(hash, row);
+            if self.counters[i] < SKETCH_MAX {
+                self.counters[i] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= 10 * self.width {
+            for c in self.counters.iter_mut() {
+                *c /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, hash: u64) -> u8 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[self.index(hash, row)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Memory budget of a cache, every write of the cache goes through it while
+/// holding the cache's pending lock.
+pub(crate) struct Evictor<K, V> {
+    budget: usize,
+    policy: Policy,
+    weigher: Box<dyn Weigher<K, V>>,
+    hasher: RandomState,
+    /// Width of the frequency sketch, about the expected number of items.
+    width: usize,
+    tracker: Mutex<Tracker<K>>,
+    /// Hashes of the keys read since the last write, per stripe.
+    reads: [ReadStripe; READ_STRIPES],
+    evictions: AtomicU64,
+}
+
+impl<K: Eq + Hash + Clone, V> Evictor<K, V> {
+    pub fn new(
+        budget: usize,
+        policy: Policy,
+        weigher: Box<dyn Weigher<K, V>>,
+        width: usize,
+    ) -> Self {
+        Self {
+            budget,
+            policy,
+            weigher,
+            hasher: RandomState::new(),
+            width,
+            tracker: Mutex::new(Tracker::new(policy, width)),
+            reads: Default::default(),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// An empty tracker, e.g. for a bulk load replacing every item.
+    pub fn tracker(&self) -> Tracker<K> {
+        Tracker::new(self.policy, self.width)
+    }
+
+    /// Replaces the tracked items with the ones of `tracker`.
+    pub fn replace(&self, tracker: Tracker<K>) {
+        *self.tracker.lock() = tracker;
+    }
+
+    /// Records a lookup of `key`, found or not, in the read buffer of the
+    /// thread. Never blocks: the lookup is dropped if another reader or a
+    /// writer is busy with the buffer or if it is full.
+    pub fn read<Q: ?Sized + Hash>(&self, key: &Q) {
+        if !self.policy.tracks_reads() {
+            return;
+        }
+        let stripe = &self.reads[STRIPE.with(|stripe| *stripe)];
+        if let Some(mut reads) = stripe.0.try_lock() {
+            if reads.len() < READ_BUFFER / READ_STRIPES {
+                reads.push(self.hasher.hash_one(key));
+            }
+        }
+    }
+
+    /// Like `prepare`, committed at once.
+    #[cfg(test)]
+    pub fn put(&self, key: &K, value: &V) -> Result<Vec<K>> {
+        Ok(self.prepare(key, value)?.commit(key))
+    }
+
+    /// Accounts for writing `value` to `key` once the admission is
+    /// committed, which returns the keys to evict first. Fails with
+    /// `CannotWrite` if the write can not fit. The reads buffered since the
+    /// last write are applied first, one buffer after the other, so the
+    /// recency of reads by different threads is only approximate.
+    pub fn prepare(&self, key: &K, value: &V) -> Result<Admission<'_, K, V>> {
+        let mut tracker = self.tracker.lock();
+        for stripe in &self.reads {
+            for hash in stripe.0.lock().drain(..) {
+                if let Some(sketch) = &mut tracker.sketch {
+                    sketch.increment(hash);
+                }
+                tracker.touch(hash);
+            }
+        }
+        let plan = self.plan(&mut tracker, key, value)?;
+        Ok(Admission {
+            evictor: self,
+            tracker,
+            plan,
+        })
+    }
+
+    /// Like `put` on the items of `tracker`.
+    pub fn admit(&self, tracker: &mut Tracker<K>, key: &K, value: &V) -> Result<Vec<K>> {
+        let plan = self.plan(tracker, key, value)?;
+        Ok(self.apply(tracker, key, plan))
+    }
+
+    /// Picks the items to evict for the write, only the frequency of `key`
+    /// is counted.
+    fn plan(&self, tracker: &mut Tracker<K>, key: &K, value: &V) -> Result<Plan<K>> {
+        let hash = self.hasher.hash_one(key);
+        let weight = self.weigher.weigh(key, value);
+        if let Some(sketch) = &mut tracker.sketch {
+            sketch.increment(hash);
+        }
+        let old = tracker.items.get(key).map(|item| item.weight);
+        let need = (tracker.weight - old.unwrap_or(0) + weight).saturating_sub(self.budget);
+        if need == 0 {
+            return Ok(Plan {
+                hash,
+                weight,
+                victims: Vec::new(),
+            });
+        }
+        if weight > self.budget || self.policy == Policy::Reject {
+            return Err(CacheError::CannotWrite);
+        }
+
+        let mut freed = 0;
+        let mut victims = Vec::new();
+        for k in tracker.order.values().filter(|k| *k != key) {
+            if freed >= need {
+                break;
+            }
+            let item = &tracker.items[k];
+            if let (Some(sketch), None) = (&tracker.sketch, old) {
+                if sketch.estimate(hash) < sketch.estimate(item.hash) {
+                    return Err(CacheError::CannotWrite);
+                }
+            }
+            freed += item.weight;
+            victims.push(k.clone());
+        }
+        Ok(Plan {
+            hash,
+            weight,
+            victims,
+        })
+    }
+
+    /// Evicts the victims of `plan` and sets `key`, returns the victims.
+    fn apply(&self, tracker: &mut Tracker<K>, key: &K, plan: Plan<K>) -> Vec<K> {
+        for k in &plan.victims {
+            tracker.remove(k);
+        }
+        tracker.set(key, plan.hash, plan.weight, self.policy);
+        self.evictions.fetch_add(plan.victims.len() as u64, Ordering::Relaxed);
+        plan.victims
+    }
+
+    /// Sets the value of `key` without evicting, e.g. restored by a rollback.
+    pub fn restore(&self, key: &K, value: Option<&V>) {
+        let mut tracker = self.tracker.lock();
+        match value {
+            Some(value) => {
+                let weight = self.weigher.weigh(key, value);
+                tracker.set(key, self.hasher.hash_one(key), weight, self.policy);
+            }
+            None => tracker.remove(key),
+        }
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.tracker.lock().remove(key);
+    }
+
+    /// Weight of the items once the pending writes are flushed.
+    pub fn weight(&self) -> usize {
+        self.tracker.lock().weight
+    }
+
+    /// Items evicted so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+struct Plan<K> {
+    hash: u64,
+    weight: usize,
+    victims: Vec<K>,
+}
+
+/// A write that fits the budget, see `Evictor::prepare`. The items are locked
+/// until it is committed or dropped, dropping it leaves them unchanged.
+pub(crate) struct Admission<'a, K, V> {
+    evictor: &'a Evictor<K, V>,
+    tracker: MutexGuard<'a, Tracker<K>>,
+    plan: Plan<K>,
+}
+
+impl<K: Eq + Hash + Clone, V> Admission<'_, K, V> {
+    /// Sets `key`, the key the admission was prepared for, and returns the
+    /// keys to evict first.
+    pub fn commit(mut self, key: &K) -> Vec<K> {
+        self.evictor.apply(&mut self.tracker, key, self.plan)
+    }
+}
+
+impl<K, V> fmt::Debug for Evictor<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Evictor")
+            .field("budget", &self.budget)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evictor(budget: usize, policy: Policy) -> Evictor<u32, usize> {
+        Evictor::new(budget, policy, Box::new(|_: &u32, v: &usize| *v), 64)
+    }
+
+    #[test]
+    fn test_policies() {
+        let lru = evictor(10, Policy::Lru);
+        assert_eq!(lru.put(&1, &4), Ok(vec![]));
+        assert_eq!(lru.put(&2, &4), Ok(vec![]));
+        lru.read(&1);
+        assert_eq!(lru.put(&3, &4), Ok(vec![2]));
+        assert_eq!(lru.put(&4, &11), Err(CacheError::CannotWrite));
+        assert_eq!((lru.weight(), lru.evictions()), (8, 1));
+
+        let fifo = evictor(10, Policy::Fifo);
+        assert_eq!(fifo.put(&1, &4), Ok(vec![]));
+        assert_eq!(fifo.put(&2, &4), Ok(vec![]));
+        fifo.read(&1);
+        assert_eq!(fifo.put(&1, &5), Ok(vec![]));
+        assert_eq!(fifo.put(&3, &4), Ok(vec![1]));
+
+        let reject = evictor(10, Policy::Reject);
+        assert_eq!(reject.put(&1, &8), Ok(vec![]));
+        assert_eq!(reject.put(&2, &4), Err(CacheError::CannotWrite));
+        assert_eq!(reject.put(&1, &10), Ok(vec![]));
+        reject.remove(&1);
+        assert_eq!(reject.weight(), 0);
+    }
+
+    #[test]
+    fn test_lfu_admission() {
+        let lfu = evictor(8, Policy::Lfu);
+        assert_eq!(lfu.put(&1, &4), Ok(vec![]));
+        assert_eq!(lfu.put(&2, &4), Ok(vec![]));
+        for _ in 0..3 {
+            lfu.read(&1);
+            lfu.read(&2);
+        }
+        // Key 3 was used once, less than the key it would evict
+        assert_eq!(lfu.put(&3, &4), Err(CacheError::CannotWrite));
+        // Reads from other threads count too
+        std::thread::scope(|s| {
+            for _ in 0..5 {
+                s.spawn(|| lfu.read(&3));
+            }
+        });
+        assert_eq!(lfu.put(&3, &4), Ok(vec![1]));
+    }
+}
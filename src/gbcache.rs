@@ -1,134 +1,1170 @@
 /// Green-Blue Cache
 ///
 ///
+use dashmap::mapref::one::MappedRef;
 use dashmap::DashMap;
-use std::borrow::{Borrow, BorrowMut};
-use std::fmt::Display;
+use std::borrow::Borrow;
+use std::collections::{HashSet, VecDeque};
+use std::convert::Infallible;
 use std::hash::Hash;
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use parking_lot::RwLock;
+use tokio::sync::broadcast;
 use tokio::time::Duration;
 
-pub type Result<T> = std::result::Result<T, CacheError>;
+use crate::cache::{CacheReader, CacheStatus, CacheWriter, MapStatus, Published};
+pub use crate::error::{CacheError, Result};
+use crate::evict::{Evictor, Policy, Tracker, Weigher};
+use crate::quiesce::{ReadToken, ReaderGate};
+pub use crate::quiesce::DRAIN_TIMEOUT;
+use crate::snapshot::{self, Checksum, Codec};
+use crate::ttl::Expiring;
 
-const THROTTLE: Duration = Duration::from_nanos(1);
+/// Flushes a subscriber can fall behind before it misses some.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// A write or the value it overwrote, `None` for no value.
+type Change<K, V> = (K, Option<Expiring<V>>);
+
+/// Writes of a flush, or the values they overwrote.
+type Batch<K, V> = Vec<Change<K, V>>;
+
+/// Flushes `rollback` can undo by default.
+pub const HISTORY: usize = 1;
 
 #[derive(Debug)]
 pub struct GreenBlueCache<K, V>
 where
     K: Eq + Hash + Sized,
 {
-    caches: [Arc<DashMap<K, V>>; 2],
-    current: RwLock<usize>,
-    pending: RwLock<Vec<(K, V)>>,
+    caches: [DashMap<K, Expiring<V>>; 2],
+    gate: ReaderGate,
+    /// Number of times pending writes were published.
+    generation: AtomicU64,
+    last_flush: RwLock<Option<SystemTime>>,
+    /// Writes since the last flush, `None` is a tombstone for a removed key.
+    pending: RwLock<Batch<K, V>>,
+    /// The inactive map still had readers when the last flush timed out and
+    /// is missing the pending log. Only changed while holding `pending`.
+    stale: AtomicBool,
+    /// The inactive map must be copied from the current one before the
+    /// pending log is replayed, left by a bulk load that timed out. Only
+    /// changed while holding `pending`.
+    resync: AtomicBool,
+    /// A `BulkLoad` owns the inactive map, writes and flushes are refused.
+    loading: AtomicBool,
+    /// Entries at the head of `pending` already published by a timed out
+    /// flush. Only changed while holding `pending`.
+    published: AtomicUsize,
+    events: broadcast::Sender<Arc<Published<K, V>>>,
+    /// Undo log of each of the last `history_len` flushes, newest last: the
+    /// previous value of every key the flush wrote.
+    history: Mutex<VecDeque<Batch<K, V>>>,
+    history_len: usize,
+    /// Time to live of the values written by `put`.
+    default_ttl: Option<Duration>,
+    /// A value with a deadline was written, until then `expire` has nothing
+    /// to scan for.
+    deadlines: AtomicBool,
+    /// Memory budget of the items, none by default.
+    evictor: Option<Evictor<K, V>>,
+    drain_timeout: Duration,
     nowrite_lock: Mutex<()>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum CacheError {
-    NotFound,
-    CannotSwitch,
-    CannotWrite,
-}
-
-impl std::fmt::Display for CacheError {
-    fn fmt(
-        &self,
-        formatter: &mut std::fmt::Formatter<'_>,
-    ) -> std::result::Result<(), std::fmt::Error> {
-        formatter.write_str(&format!("{:?}", self))?;
-        Ok(())
-    }
-}
-
-impl std::error::Error for CacheError {}
-
 impl<K, V> GreenBlueCache<K, V>
 where
-    K: Eq + Hash + Sized + Clone + Display,
-    V: Clone + Display,
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
 {
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             caches: [
-                Arc::new(DashMap::with_capacity(capacity)),
-                Arc::new(DashMap::with_capacity(capacity)),
+                DashMap::with_capacity(capacity),
+                DashMap::with_capacity(capacity),
             ],
-            current: RwLock::new(0),
+            gate: ReaderGate::default(),
+            generation: AtomicU64::new(0),
+            last_flush: RwLock::new(None),
             pending: RwLock::new(Vec::with_capacity(capacity)),
+            stale: AtomicBool::new(false),
+            resync: AtomicBool::new(false),
+            loading: AtomicBool::new(false),
+            published: AtomicUsize::new(0),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            history: Mutex::new(VecDeque::new()),
+            history_len: HISTORY,
+            default_ttl: None,
+            deadlines: AtomicBool::new(false),
+            evictor: None,
+            drain_timeout: DRAIN_TIMEOUT,
             nowrite_lock: Mutex::new(()),
         }
     }
 
+    /// Sets how long `flush` waits for readers to leave the retired map.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets how many flushes `rollback` can undo, 0 disables it. Each one
+    /// keeps a copy of the previous value of every key it wrote.
+    pub fn history(mut self, flushes: usize) -> Self {
+        self.history_len = flushes;
+        self
+    }
+
+    /// Sets the time to live of the values written by `put` and `BulkLoad`.
+    pub fn default_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Keeps the items weighed by `weigher` within `bytes`, writes over it
+    /// evict or fail as `policy` says, see `evict`.
+    pub fn memory_budget<W>(mut self, bytes: usize, policy: Policy, weigher: W) -> Self
+    where
+        W: Weigher<K, V> + 'static,
+    {
+        let width = self.caches[0].capacity();
+        self.evictor = Some(Evictor::new(bytes, policy, Box::new(weigher), width));
+        self
+    }
+
+    /// Receives the writes of every flush from now on, as soon as readers
+    /// can see them. A subscriber more than `EVENTS_CAPACITY` flushes behind
+    /// gets `RecvError::Lagged`.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Published<K, V>>> {
+        self.events.subscribe()
+    }
+
     pub fn put(&self, key: K, value: V) -> Result<()> {
-        // println!("** put {}: {}", &key, &value);
-        let i = 1 - *self.current.read();
+        self.put_expiring(key, Expiring::new(value, self.default_ttl))
+    }
+
+    /// Writes `value` expiring `ttl` from now instead of the default.
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Duration) -> Result<()> {
+        self.put_expiring(key, Expiring::new(value, Some(ttl)))
+    }
+
+    /// Writes a value with its expiry, e.g. replayed from a WAL.
+    pub fn put_expiring(&self, key: K, value: Expiring<V>) -> Result<()> {
+        // println!("** put {}: {}", &key, &value.value);
+        self.put_logged(key, value, |_, _| Ok::<_, Infallible>(())).map(drop)
+    }
+
+    /// Like `put_expiring`, but calls `log` with the write once the cache
+    /// accepted it and before applying it, e.g. to append it to a WAL. A
+    /// write the cache refuses is not logged, and a failing `log` leaves the
+    /// cache unchanged and returns the error in the inner result.
+    pub fn put_logged<E>(
+        &self,
+        key: K,
+        value: Expiring<V>,
+        log: impl FnOnce(&K, &Expiring<V>) -> std::result::Result<(), E>,
+    ) -> Result<std::result::Result<(), E>> {
         let mut pending = self.pending.write();
-        let cache = self.caches[i].clone();
-        pending.push((key.clone(), value.clone()));
-        cache.insert(key, value);
+        if self.loading.load(Ordering::Relaxed) {
+            return Err(CacheError::CannotWrite);
+        }
+        let admission = match &self.evictor {
+            Some(evictor) => Some(evictor.prepare(&key, &value.value)?),
+            None => None,
+        };
+        if let Err(e) = log(&key, &value) {
+            return Ok(Err(e));
+        }
+        if let Some(admission) = admission {
+            // Evicted like removed, the flush publishes both
+            for k in admission.commit(&key) {
+                if !self.stale.load(Ordering::Relaxed) {
+                    self.caches[1 - self.gate.current()].remove(&k);
+                }
+                pending.push((k, None));
+            }
+        }
+        if value.expires.is_some() {
+            self.deadlines.store(true, Ordering::Relaxed);
+        }
+        if !self.stale.load(Ordering::Relaxed) {
+            self.caches[1 - self.gate.current()].insert(key.clone(), value.clone());
+        }
+        pending.push((key, Some(value)));
         // sleep(THROTTLE).await;
-        Ok(())
+        Ok(Ok(()))
+    }
+
+    /// Expiry the values written by `put` get from now.
+    pub fn expiring(&self, value: V) -> Expiring<V> {
+        Expiring::new(value, self.default_ttl)
+    }
+
+    /// Removes `key`, readers keep seeing it until the next `flush`.
+    pub fn remove(&self, key: K) -> Result<()> {
+        self.remove_logged(key, |_| Ok::<_, Infallible>(())).map(drop)
+    }
+
+    /// Like `remove`, calling `log` first like `put_logged`.
+    pub fn remove_logged<E>(
+        &self,
+        key: K,
+        log: impl FnOnce(&K) -> std::result::Result<(), E>,
+    ) -> Result<std::result::Result<(), E>> {
+        let mut pending = self.pending.write();
+        if self.loading.load(Ordering::Relaxed) {
+            return Err(CacheError::CannotWrite);
+        }
+        if let Err(e) = log(&key) {
+            return Ok(Err(e));
+        }
+        if !self.stale.load(Ordering::Relaxed) {
+            self.caches[1 - self.gate.current()].remove(&key);
+        }
+        if let Some(evictor) = &self.evictor {
+            evictor.remove(&key);
+        }
+        pending.push((key, None));
+        Ok(Ok(()))
     }
 
-    pub fn get(&self, keys: &[K]) -> Vec<Option<V>> {
-        let i = *self.current.read();
-        let cache = self.caches[i].clone();
-        keys.iter()
-            .map(|k| cache.get(k).map(|v| v.clone()))
-            .collect()
+    pub fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        self.get_with_generation(keys).1
     }
 
-    pub fn flush(&self) -> Result<()> {
+    /// Looks up `keys` and returns the generation they were read at.
+    ///
+    /// The generation is loaded before entering the map, a read racing with
+    /// a flush may see the new items under the previous generation but never
+    /// the other way around.
+    pub fn get_with_generation<'q, Q, I>(&self, keys: I) -> (u64, Vec<Option<V>>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        let view = self.read();
+        let values = keys.into_iter().map(|k| view.get(k).map(|v| v.clone())).collect();
+        (view.generation(), values)
+    }
+
+    /// Calls `f` with every key of the batch and its value, borrowed from the
+    /// map instead of cloned, returns the generation they were read at.
+    pub fn get_with<'q, Q, I, F>(&self, keys: I, mut f: F) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        let view = self.read();
+        for k in keys {
+            f(k, view.get(k).as_deref());
+        }
+        view.generation()
+    }
+
+    /// Enters the published map through the reader gate, see `ReadView`.
+    pub fn read(&self) -> ReadView<'_, K, V> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        // Expiry is judged at the time the view is taken
+        let now = SystemTime::now();
+        let token = self.gate.enter();
+        ReadView {
+            cache: &self.caches[token.index()],
+            evictor: self.evictor.as_ref(),
+            _token: token,
+            generation,
+            now,
+        }
+    }
+
+    /// Reads at generation `min_generation` or later, fails with
+    /// `GenerationNotReached` if that flush is not visible yet, e.g. on a
+    /// replica that has not caught up.
+    pub fn get_at<'q, Q, I>(&self, keys: I, min_generation: u64) -> Result<(u64, Vec<Option<V>>)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        let (generation, values) = self.get_with_generation(keys);
+        if generation < min_generation {
+            return Err(CacheError::GenerationNotReached);
+        }
+        Ok((generation, values))
+    }
+
+    /// Publishes the pending writes, returns the new generation.
+    ///
+    /// New readers are switched to the map that already holds the pending
+    /// writes, then `flush` waits for the readers still on the retired map
+    /// before replaying the pending log into it, so no reader ever sees a
+    /// partially applied batch.
+    ///
+    /// Fails with `CannotSwitch` if another flush or a bulk load is running, or if readers
+    /// did not leave the retired map within the drain timeout. In the latter
+    /// case the new data is already visible and the pending log is kept and
+    /// replayed by the next flush.
+    pub fn flush(&self) -> Result<u64> {
         let nowrite_lock = self
             .nowrite_lock
             .try_lock()
             .map_err(|_| CacheError::CannotSwitch)?;
-        let i = {
-            let mut current = self.current.write();
-            let i = *current;
-            *current = 1 - i;
-            i
-        };
+        // Writers wait for the whole flush
+        let mut pending = self.pending.write();
+        if self.loading.load(Ordering::Relaxed) {
+            return Err(CacheError::CannotSwitch);
+        }
+        self.catch_up(&pending)?;
+        // A timed out flush already recorded the writes it published
+        self.record(&pending[self.published.load(Ordering::Relaxed)..]);
+        let generation = self.switch(&mut pending)?;
+        drop(nowrite_lock);
+        Ok(generation)
+    }
+
+    /// Publishes the values the last flush not yet rolled back overwrote, as
+    /// a new generation, and returns it. Up to `history` flushes can be
+    /// undone in a row, a bulk load can not be.
+    ///
+    /// Fails with `CannotWrite` while unpublished writes are pending, they
+    /// would be published along, with `NotFound` when there is nothing left
+    /// to undo and like `flush` with `CannotSwitch`. The writes a timed out
+    /// flush published can be rolled back before a flush succeeds.
+    pub fn rollback(&self) -> Result<u64> {
+        let _flush = self
+            .nowrite_lock
+            .try_lock()
+            .map_err(|_| CacheError::CannotSwitch)?;
+        let mut pending = self.pending.write();
+        if self.loading.load(Ordering::Relaxed) {
+            return Err(CacheError::CannotSwitch);
+        }
+        if pending.len() > self.published.load(Ordering::Relaxed) {
+            return Err(CacheError::CannotWrite);
+        }
+        self.catch_up(&pending)?;
+        // Both maps hold the writes published by a timed out flush
+        self.published.store(0, Ordering::Relaxed);
+        let undo = self.history.lock().unwrap().pop_back();
+        let undo = undo.ok_or(CacheError::NotFound)?;
+        self.replay(1 - self.gate.current(), &undo);
+        if let Some(evictor) = &self.evictor {
+            for (k, v) in &undo {
+                evictor.restore(k, v.as_ref().map(|v| &v.value));
+            }
+        }
+        *pending = undo;
+        self.switch(&mut pending)
+    }
 
-        // while Arc::strong_count(&self.caches[i]) > 1 {
-        //     println!(
-        //         "** flush: wait readers rc={:?}",
-        //         Arc::strong_count(&self.caches[i])
-        //     );
-        //     std::thread::sleep(THROTTLE);
-        // }
-        // assert_eq!(Arc::strong_count(&self.caches[i]), 1);
-        //println!("** flush: DONE wait readers");
-        // TODO
+    /// Catches up on the map a timed out flush could not replay into.
+    fn catch_up(&self, pending: &[Change<K, V>]) -> Result<()> {
+        if self.stale.load(Ordering::Relaxed) {
+            let i = 1 - self.gate.current();
+            if !self.gate.wait_drained(i, self.drain_timeout) {
+                return Err(CacheError::CannotSwitch);
+            }
+            if self.resync.swap(false, Ordering::Relaxed) {
+                self.copy_current(i);
+            }
+            self.replay(i, pending);
+            self.stale.store(false, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Keeps the values readers see for the keys about to be published, an
+    /// empty flush has nothing to undo and keeps the history as it is.
+    fn record(&self, pending: &[Change<K, V>]) {
+        if self.history_len == 0 || pending.is_empty() {
+            return;
+        }
+        let cache = &self.caches[self.gate.current()];
+        let mut seen = HashSet::new();
+        let undo = pending
+            .iter()
+            .filter(|(k, _)| seen.insert(k))
+            .map(|(k, _)| (k.clone(), cache.get(k).map(|v| v.clone())))
+            .collect();
+        let mut history = self.history.lock().unwrap();
+        if history.len() == self.history_len {
+            history.pop_front();
+        }
+        history.push_back(undo);
+    }
+
+    /// Switches readers to the inactive map, which already holds `pending`,
+    /// and replays `pending` into the retired one.
+    fn switch(&self, pending: &mut Batch<K, V>) -> Result<u64> {
+        let i = self.gate.switch();
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        *self.last_flush.write() = Some(SystemTime::now());
+        self.publish(generation, pending);
+        if !self.gate.wait_drained(i, self.drain_timeout) {
+            self.stale.store(true, Ordering::Relaxed);
+            return Err(CacheError::CannotSwitch);
+        }
 
         // Insert pending items in inactive cache
-        let pending = self.pending.read();
-        let cache = self.caches[i].clone();
-        println!("*** {:?} Flushing...", std::thread::current().id());
-        for (k, v) in pending.iter() {
-            cache.insert(k.clone(), v.clone());
+        self.replay(i, pending);
+        pending.clear();
+        self.published.store(0, Ordering::Relaxed);
+        Ok(generation)
+    }
+
+    /// Sends the writes that became visible with `generation` to subscribers.
+    fn publish(&self, generation: u64, pending: &[Change<K, V>]) {
+        let published = self.published.swap(pending.len(), Ordering::Relaxed);
+        if self.events.receiver_count() > 0 {
+            let changes = pending[published..]
+                .iter()
+                .map(|(k, v)| (k.clone(), v.as_ref().map(|v| v.value.clone())))
+                .collect();
+            // Only fails when every subscriber is gone
+            let _ = self.events.send(Arc::new(Published {
+                generation,
+                changes,
+                replaced: false,
+            }));
         }
-        drop(pending);
+    }
+
+    /// Makes map `i` a copy of the map readers are served from.
+    fn copy_current(&self, i: usize) {
+        let (from, to) = (&self.caches[1 - i], &self.caches[i]);
+        to.clear();
+        for item in from.iter() {
+            to.insert(item.key().clone(), item.value().clone());
+        }
+    }
+
+    /// Starts replacing every item of the cache, see `BulkLoad`.
+    ///
+    /// Fails with `CannotWrite` while writes are pending, they would be lost
+    /// by the replacement, and with `CannotSwitch` if a flush or another load
+    /// is running or the inactive map still has readers.
+    pub fn bulk_load(&self) -> Result<BulkLoad<'_, K, V>> {
+        let _flush = self
+            .nowrite_lock
+            .try_lock()
+            .map_err(|_| CacheError::CannotSwitch)?;
+        let pending = self.pending.write();
+        if self.loading.load(Ordering::Relaxed) {
+            return Err(CacheError::CannotSwitch);
+        }
+        if !pending.is_empty() {
+            return Err(CacheError::CannotWrite);
+        }
+        let i = 1 - self.gate.current();
+        if !self.gate.wait_drained(i, self.drain_timeout) {
+            return Err(CacheError::CannotSwitch);
+        }
+        // The load replaces the inactive map, whatever it was missing
+        self.stale.store(false, Ordering::Relaxed);
+        self.resync.store(false, Ordering::Relaxed);
+        self.loading.store(true, Ordering::Relaxed);
+        self.caches[i].clear();
+        Ok(BulkLoad {
+            cache: self,
+            index: i,
+            tracker: self.evictor.as_ref().map(Evictor::tracker),
+            checksum: Checksum::default(),
+            done: false,
+        })
+    }
+
+    /// A `BulkLoad` is in progress.
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Relaxed)
+    }
+
+    /// Queues the removal of the items readers see as expired, returns how
+    /// many. Readers already skip them, the removals free them once flushed.
+    ///
+    /// The current map is scanned without blocking writers, the expired keys
+    /// are then checked again under the pending lock: a key written since the
+    /// last flush is left alone whatever its old value. Nothing is scanned
+    /// until a value with a deadline is written.
+    pub fn expire(&self) -> usize {
+        if !self.deadlines.load(Ordering::Relaxed) {
+            return 0;
+        }
+        let now = SystemTime::now();
+        let expired: Vec<K> = self.caches[self.gate.current()]
+            .iter()
+            .filter(|item| item.value().is_expired(now))
+            .map(|item| item.key().clone())
+            .collect();
+        if expired.is_empty() {
+            return 0;
+        }
+
         let mut pending = self.pending.write();
-        pending.clear();
-        println!("*** Flush DONE.");
-        drop(nowrite_lock);
+        if self.loading.load(Ordering::Relaxed) {
+            return 0;
+        }
+        let written: HashSet<&K> = pending.iter().map(|(k, _)| k).collect();
+        // No flush can switch or write the current map while `pending` is held
+        let current = &self.caches[self.gate.current()];
+        let expired: Vec<K> = expired
+            .into_iter()
+            .filter(|k| !written.contains(k))
+            .filter(|k| current.get(k).is_some_and(|v| v.is_expired(now)))
+            .collect();
+        if !self.stale.load(Ordering::Relaxed) {
+            let inactive = &self.caches[1 - self.gate.current()];
+            for k in &expired {
+                inactive.remove(k);
+            }
+        }
+        if let Some(evictor) = &self.evictor {
+            for k in &expired {
+                evictor.remove(k);
+            }
+        }
+        let n = expired.len();
+        pending.extend(expired.into_iter().map(|k| (k, None)));
+        n
+    }
+
+    fn replay(&self, i: usize, pending: &[Change<K, V>]) {
+        let cache = &self.caches[i];
+        for (k, v) in pending.iter() {
+            match v {
+                Some(v) => {
+                    cache.insert(k.clone(), v.clone());
+                }
+                None => {
+                    cache.remove(k);
+                }
+            }
+        }
+    }
+
+    /// Saves the items visible to readers to `path` with their expiry and
+    /// generation, pending writes and expired items are not included.
+    /// Writers wait until the snapshot is written.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        // Without the pending lock no flush can switch or write the current map
+        let _pending = self.pending.write();
+        let now = SystemTime::now();
+        let mut w = snapshot::Writer::create_with_generation(path, self.generation())?;
+        for item in self.caches[self.gate.current()].iter() {
+            if !item.value().is_expired(now) {
+                w.write(item.key(), item.value())?;
+            }
+        }
+        w.finish()
+    }
+
+    /// Loads a snapshot saved by `save_snapshot` on top of the current items
+    /// and publishes it, returns the number of items loaded. Items that
+    /// expired since the snapshot was saved are skipped. The load is published
+    /// past the saved generation, so generations keep growing across a
+    /// restart. Like a bulk load, the load clears the rollback history:
+    /// undoing it would drop the items.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let now = SystemTime::now();
+        let mut n = 0;
+        let (generation, entries) = snapshot::load_with_generation::<K, Expiring<V>>(path)?;
+        for (k, v) in entries {
+            if v.is_expired(now) {
+                continue;
+            }
+            match self.put_expiring(k, v) {
+                Ok(()) => n += 1,
+                // Over the memory budget, e.g. lowered since the save
+                Err(CacheError::CannotWrite) if !self.is_loading() => {}
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        self.generation.fetch_max(generation, Ordering::SeqCst);
+        self.flush().map_err(io::Error::other)?;
+        self.history.lock().unwrap().clear();
+        Ok(n)
+    }
+
+    /// Number of items visible to readers.
+    pub fn len(&self) -> usize {
+        self.caches[self.gate.current()].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of writes waiting for the next `flush`.
+    pub fn pending_len(&self) -> usize {
+        self.pending.read().len()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Index of the map readers are currently served from.
+    pub fn current(&self) -> usize {
+        self.gate.current()
+    }
+
+    /// Weight of the items once the pending writes are flushed, `None`
+    /// without a memory budget.
+    pub fn weight(&self) -> Option<usize> {
+        self.evictor.as_ref().map(Evictor::weight)
+    }
+
+    /// Items evicted to stay within the memory budget so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictor.as_ref().map_or(0, Evictor::evictions)
+    }
+
+    pub fn status(&self) -> CacheStatus {
+        CacheStatus {
+            maps: (0..2)
+                .map(|i| MapStatus {
+                    items: self.caches[i].len(),
+                    shards: self.caches[i].shards().len(),
+                    readers: self.gate.readers(i),
+                })
+                .collect(),
+            pending: self.pending.read().len(),
+            current: self.gate.current(),
+            generation: self.generation(),
+            last_flush: *self.last_flush.read(),
+        }
+    }
+}
+
+/// Published items of a `GreenBlueCache` pinned for reading.
+///
+/// Values are borrowed from the map instead of cloned, each borrow holding a
+/// read lock on its shard of the `DashMap`. The view also holds a token of
+/// the reader gate: a flush waits for the views on the map it retires before
+/// applying writes to it, so views should be dropped quickly, one held past
+/// the switch timeout fails the flush with `CannotSwitch`. A value that
+/// expires while the view is held stays readable through it.
+pub struct ReadView<'a, K, V> {
+    cache: &'a DashMap<K, Expiring<V>>,
+    evictor: Option<&'a Evictor<K, V>>,
+    _token: ReadToken<'a>,
+    generation: u64,
+    now: SystemTime,
+}
+
+impl<K: Eq + Hash + Clone, V> ReadView<'_, K, V> {
+    /// The value of `key`, the shard holding it is read-locked until the
+    /// returned reference is dropped.
+    pub fn get<Q>(&self, key: &Q) -> Option<MappedRef<'_, K, Expiring<V>, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        // Only a cache with a memory budget records its reads
+        if let Some(evictor) = self.evictor {
+            evictor.read(key);
+        }
+        let now = self.now;
+        self.cache.get(key)?.try_map(|e| e.live(now)).ok()
+    }
+
+    /// Generation of the items, see `GreenBlueCache::get_with_generation`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+/// Full replacement of the items of a `GreenBlueCache`.
+///
+/// Entries are inserted straight into the inactive map, readers keep the
+/// current items until `commit` switches them to the loaded ones in one step.
+/// Dropping the load without committing restores the inactive map and leaves
+/// the cache as it was.
+pub struct BulkLoad<'a, K, V>
+where
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
+{
+    cache: &'a GreenBlueCache<K, V>,
+    index: usize,
+    /// Weight of the loaded items, with a memory budget.
+    tracker: Option<Tracker<K>>,
+    checksum: Checksum,
+    done: bool,
+}
+
+impl<K, V> BulkLoad<'_, K, V>
+where
+    K: Eq + Hash + Sized + Clone + Codec,
+    V: Clone + Codec,
+{
+    /// Inserts `value` with the default time to live of the cache, the
+    /// checksum only covers the key and value.
+    ///
+    /// With a memory budget, entries loaded earlier are evicted as the policy
+    /// says, or the insert fails with `CannotWrite` and the load should be
+    /// dropped.
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        let cache = self.cache;
+        if let (Some(evictor), Some(tracker)) = (&cache.evictor, &mut self.tracker) {
+            // Not visible yet, evicted straight from the loaded map
+            for k in evictor.admit(tracker, &key, &value)? {
+                cache.caches[self.index].remove(&k);
+            }
+        }
+        self.checksum.add(&key, &value);
+        let value = cache.expiring(value);
+        if value.expires.is_some() {
+            cache.deadlines.store(true, Ordering::Relaxed);
+        }
+        cache.caches[self.index].insert(key, value);
         Ok(())
     }
 
-    pub fn status(&self) {
-        println!("Thread {:?} ************ Green: {}_items {}_shards {}_readers // Blue: {}_items {}_shards {}_readers // Pending: {} Current: {}",
-            std::thread::current().id(),
-            self.caches[0].len(),
-            self.caches[0].shards().len(),
-            Arc::strong_count(&self.caches[0]),
-            self.caches[1].len(),
-            self.caches[1].shards().len(),
-            Arc::strong_count(&self.caches[1]),
-            self.pending.read().len(),
-            *self.current.read(),
-        );
+    /// Entries inserted so far.
+    pub fn count(&self) -> u64 {
+        self.checksum.count()
+    }
+
+    /// Publishes the loaded items if `count` and `checksum` match the entries
+    /// inserted, see `snapshot::Checksum`, and returns the new generation.
+    /// Fails with `LoadMismatch` otherwise, the current items are kept.
+    pub fn commit(mut self, count: u64, checksum: u64) -> Result<u64> {
+        if count != self.checksum.count() || checksum != self.checksum.sum() {
+            return Err(CacheError::LoadMismatch);
+        }
+        let cache = self.cache;
+        let _pending = cache.pending.write();
+        // The undo logs do not restore the items the load dropped
+        cache.history.lock().unwrap().clear();
+        if let (Some(evictor), Some(tracker)) = (&cache.evictor, self.tracker.take()) {
+            evictor.replace(tracker);
+        }
+        let i = cache.gate.switch();
+        let generation = cache.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        *cache.last_flush.write() = Some(SystemTime::now());
+        if cache.events.receiver_count() > 0 {
+            let _ = cache.events.send(Arc::new(Published {
+                generation,
+                changes: Vec::new(),
+                replaced: true,
+            }));
+        }
+        if cache.gate.wait_drained(i, cache.drain_timeout) {
+            cache.copy_current(i);
+        } else {
+            // The next flush copies the loaded items once the readers are gone
+            cache.stale.store(true, Ordering::Relaxed);
+            cache.resync.store(true, Ordering::Relaxed);
+        }
+        cache.loading.store(false, Ordering::Relaxed);
+        self.done = true;
+        Ok(generation)
+    }
+}
+
+impl<K, V> Drop for BulkLoad<'_, K, V>
+where
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        if !self.done {
+            let _pending = self.cache.pending.write();
+            self.cache.copy_current(self.index);
+            self.cache.loading.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K, V> CacheReader<K, V> for GreenBlueCache<K, V>
+where
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
+{
+    fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        GreenBlueCache::get(self, keys)
+    }
+
+    fn get_with<'q, Q, I, F>(&self, keys: I, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        GreenBlueCache::get_with(self, keys, f);
+    }
+}
+
+impl<K, V> CacheWriter<K, V> for GreenBlueCache<K, V>
+where
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
+{
+    fn put(&self, key: K, value: V) -> Result<()> {
+        GreenBlueCache::put(self, key, value)
+    }
+
+    fn flush(&self) -> Result<u64> {
+        GreenBlueCache::flush(self)
+    }
+
+    fn status(&self) -> CacheStatus {
+        GreenBlueCache::status(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_remove() {
+        let cache = GreenBlueCache::with_capacity(16);
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.put(2, 200), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+
+        assert_eq!(cache.remove(1), Ok(()));
+        assert_eq!(vec![Some(100), Some(200)], cache.get(&[1, 2]));
+
+        assert_eq!(cache.flush(), Ok(2));
+        assert_eq!(vec![None, Some(200)], cache.get(&[1, 2]));
+
+        // The tombstone was replayed into the other map as well
+        assert_eq!(cache.put(3, 300), Ok(()));
+        assert_eq!(cache.flush(), Ok(3));
+        assert_eq!(vec![None, Some(200), Some(300)], cache.get(&[1, 2, 3]));
+
+        // Put after remove within one batch wins
+        assert_eq!(cache.remove(2), Ok(()));
+        assert_eq!(cache.put(2, 2000), Ok(()));
+        assert_eq!(cache.flush(), Ok(4));
+        assert_eq!(cache.flush(), Ok(5));
+        assert_eq!(vec![Some(2000)], cache.get(&[2]));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snap");
+        let cache = GreenBlueCache::with_capacity(16);
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(cache.put(2, 200), Ok(()));
+        assert_eq!(cache.save_snapshot(&path).unwrap(), 1);
+
+        let cache = GreenBlueCache::<i32, i32>::with_capacity(16);
+        assert_eq!(cache.load_snapshot(&path).unwrap(), 1);
+        assert_eq!(vec![Some(100), None], cache.get(&[1, 2]));
+        assert_eq!(cache.pending_len(), 0);
+        // Published after the saved generation 1
+        assert_eq!(cache.generation(), 2);
+
+        // A warm start can not be rolled back to an empty cache
+        assert_eq!(cache.rollback(), Err(CacheError::NotFound));
+        assert_eq!(vec![Some(100)], cache.get(&[1]));
+    }
+
+    #[test]
+    fn test_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snap");
+        let cache = GreenBlueCache::with_capacity(16);
+        let value = Bytes::from_static(b"\x00\xff features");
+        assert_eq!(cache.put("k".to_string(), value.clone()), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+
+        // Reads share the stored buffer instead of copying it
+        let read = cache.get(&["k".to_string()]).pop().flatten().unwrap();
+        assert_eq!(read.as_ptr(), value.as_ptr());
+
+        assert_eq!(cache.save_snapshot(&path).unwrap(), 1);
+        let cache = GreenBlueCache::<String, Bytes>::with_capacity(16);
+        assert_eq!(cache.load_snapshot(&path).unwrap(), 1);
+        assert_eq!(vec![Some(value)], cache.get(&["k".to_string()]));
+    }
+
+    #[test]
+    fn test_flush_waits_for_readers() {
+        let cache = GreenBlueCache::with_capacity(16).drain_timeout(Duration::from_millis(10));
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+
+        // A reader stuck on the current map blocks the replay but not the switch
+        let token = cache.gate.enter();
+        assert_eq!(cache.put(1, 1000), Ok(()));
+        assert_eq!(cache.flush(), Err(CacheError::CannotSwitch));
+        assert_eq!(vec![Some(1000)], cache.get(&[1]));
+        assert_eq!(Some(100), cache.caches[token.index()].get(&1).map(|v| v.value));
+
+        // The retired map is left untouched until the reader is gone
+        assert_eq!(cache.put(2, 200), Ok(()));
+        assert_eq!(cache.flush(), Err(CacheError::CannotSwitch));
+        assert_eq!(vec![Some(1000), None], cache.get(&[1, 2]));
+        assert_eq!(None, cache.caches[token.index()].get(&2).map(|v| v.value));
+
+        drop(token);
+        assert_eq!(cache.flush(), Ok(3));
+        assert_eq!(vec![Some(1000), Some(200)], cache.get(&[1, 2]));
+        assert_eq!(cache.flush(), Ok(4));
+        assert_eq!(vec![Some(1000), Some(200)], cache.get(&[1, 2]));
+    }
+
+    #[test]
+    fn test_get_at() {
+        let cache = GreenBlueCache::with_capacity(16);
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.get_at(&[1], 1), Err(CacheError::GenerationNotReached));
+        let generation = cache.flush().unwrap();
+        assert_eq!(cache.get_at(&[1], generation), Ok((1, vec![Some(100)])));
+        assert_eq!(cache.get_with_generation(&[2]), (1, vec![None]));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let weigh = |_: &i32, v: &i32| *v as usize;
+        let cache = GreenBlueCache::with_capacity(16).memory_budget(10, Policy::Lru, weigh);
+        assert_eq!(cache.put(1, 4), Ok(()));
+        assert_eq!(cache.put(2, 4), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(vec![Some(4)], cache.get(&[1]));
+
+        // 2 was used least recently, its removal is published with the put
+        assert_eq!(cache.put(3, 4), Ok(()));
+        assert_eq!(vec![Some(4), Some(4), None], cache.get(&[1, 2, 3]));
+        assert_eq!(cache.flush(), Ok(2));
+        for map in &cache.caches {
+            let mut keys: Vec<_> = map.iter().map(|item| *item.key()).collect();
+            keys.sort();
+            assert_eq!(keys, vec![1, 3]);
+        }
+        assert_eq!((cache.weight(), cache.evictions()), (Some(8), 1));
+        assert_eq!(cache.put(4, 11), Err(CacheError::CannotWrite));
+
+        assert_eq!(cache.rollback(), Ok(3));
+        assert_eq!(vec![Some(4), Some(4), None], cache.get(&[1, 2, 3]));
+        assert_eq!(cache.weight(), Some(8));
+
+        // Entries loaded earlier are evicted before the load is visible
+        let mut load = cache.bulk_load().unwrap();
+        for k in 1..=3 {
+            assert_eq!(load.insert(k, 4), Ok(()));
+        }
+        let (count, sum) = (load.count(), load.checksum.sum());
+        assert_eq!(load.commit(count, sum), Ok(4));
+        assert_eq!(vec![None, Some(4), Some(4)], cache.get(&[1, 2, 3]));
+        assert_eq!(cache.weight(), Some(8));
+
+        let cache = GreenBlueCache::with_capacity(16).memory_budget(10, Policy::Reject, weigh);
+        assert_eq!(cache.put(1, 8), Ok(()));
+        assert_eq!(cache.put(2, 4), Err(CacheError::CannotWrite));
+        assert_eq!(cache.pending_len(), 1);
+    }
+
+    #[test]
+    fn test_read_view() {
+        let cache = GreenBlueCache::with_capacity(16);
+        assert_eq!(cache.put(1, "one".to_string()), Ok(()));
+        assert_eq!(cache.put_with_ttl(2, "two".to_string(), Duration::ZERO), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+
+        let view = cache.read();
+        assert_eq!(view.generation(), 1);
+        assert_eq!(view.get(&1).as_deref().map(String::as_str), Some("one"));
+        assert!(view.get(&2).is_none());
+        assert!(view.get(&3).is_none());
+        drop(view);
+
+        let mut lens = Vec::new();
+        let generation = cache.get_with(&[1, 2], |_, v| lens.push(v.map(String::len)));
+        assert_eq!((generation, lens), (1, vec![Some(3), None]));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let cache = GreenBlueCache::with_capacity(16).history(2);
+        assert_eq!(cache.rollback(), Err(CacheError::NotFound));
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(cache.put(1, 1000), Ok(()));
+        assert_eq!(cache.put(2, 200), Ok(()));
+        assert_eq!(cache.flush(), Ok(2));
+        assert_eq!(cache.put(3, 300), Ok(()));
+        assert_eq!(cache.rollback(), Err(CacheError::CannotWrite));
+        assert_eq!(cache.remove(3), Ok(()));
+        assert_eq!(cache.flush(), Ok(3));
+
+        // Only the last two flushes are kept, each rollback is a new generation
+        let mut events = cache.subscribe();
+        assert_eq!(cache.rollback(), Ok(4));
+        assert_eq!(events.try_recv().unwrap().changes, vec![(3, None)]);
+        assert_eq!(vec![Some(1000), Some(200), None], cache.get(&[1, 2, 3]));
+        assert_eq!(cache.rollback(), Ok(5));
+        assert_eq!(vec![Some(100), None], cache.get(&[1, 2]));
+        assert_eq!(cache.rollback(), Err(CacheError::NotFound));
+
+        // Both maps were rolled back
+        assert_eq!(cache.flush(), Ok(6));
+        assert_eq!(vec![Some(100), None], cache.get(&[1, 2]));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_rollback_after_empty_flush() {
+        let cache = GreenBlueCache::with_capacity(16).drain_timeout(Duration::from_millis(10));
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(cache.put(1, 666), Ok(()));
+        assert_eq!(cache.flush(), Ok(2));
+
+        // An empty flush does not push the bad upload out of the history
+        assert_eq!(cache.flush(), Ok(3));
+        assert_eq!(cache.rollback(), Ok(4));
+        assert_eq!(vec![Some(100)], cache.get(&[1]));
+
+        // A retried flush records only what the timed out one did not publish
+        let token = cache.gate.enter();
+        assert_eq!(cache.put(1, 666), Ok(()));
+        assert_eq!(cache.flush(), Err(CacheError::CannotSwitch));
+        drop(token);
+        assert_eq!(cache.flush(), Ok(6));
+        assert_eq!(cache.rollback(), Ok(7));
+        assert_eq!(vec![Some(100)], cache.get(&[1]));
+
+        // Published writes of a timed out flush do not block the rollback
+        let token = cache.gate.enter();
+        assert_eq!(cache.put(1, 666), Ok(()));
+        assert_eq!(cache.flush(), Err(CacheError::CannotSwitch));
+        drop(token);
+        assert_eq!(cache.rollback(), Ok(9));
+        assert_eq!(vec![Some(100)], cache.get(&[1]));
+        assert_eq!(cache.pending_len(), 0);
+        assert_eq!(cache.put(2, 200), Ok(()));
+        assert_eq!(cache.rollback(), Err(CacheError::CannotWrite));
+    }
+
+    #[test]
+    fn test_expire() {
+        let cache = GreenBlueCache::with_capacity(16).default_ttl(Some(Duration::from_millis(50)));
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.put_with_ttl(2, 200, Duration::from_secs(60)), Ok(()));
+        assert_eq!(cache.put(3, 300), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(vec![Some(100), Some(200), Some(300)], cache.get(&[1, 2, 3]));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(vec![None, Some(200), None], cache.get(&[1, 2, 3]));
+        assert_eq!(cache.len(), 3);
+
+        // A key written since the last flush is not removed
+        assert_eq!(cache.put_with_ttl(1, 1000, Duration::from_secs(60)), Ok(()));
+        assert_eq!(cache.expire(), 1);
+        assert_eq!(cache.flush(), Ok(2));
+        assert_eq!(vec![Some(1000), Some(200), None], cache.get(&[1, 2, 3]));
+        assert_eq!(cache.flush(), Ok(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.expire(), 0);
+
+        // Without deadlines the sweeper does not scan
+        let cache = GreenBlueCache::with_capacity(16);
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(cache.expire(), 0);
+        assert!(!cache.deadlines.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let cache = GreenBlueCache::with_capacity(16).drain_timeout(Duration::from_millis(10));
+        let mut events = cache.subscribe();
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.remove(2), Ok(()));
+        assert_eq!(cache.flush(), Ok(1));
+        let published = events.try_recv().unwrap();
+        assert_eq!(published.generation, 1);
+        assert_eq!(published.changes, vec![(1, Some(100)), (2, None)]);
+
+        // A timed out flush publishes, the next one only sends newer writes
+        let token = cache.gate.enter();
+        assert_eq!(cache.put(1, 1000), Ok(()));
+        assert_eq!(cache.flush(), Err(CacheError::CannotSwitch));
+        assert_eq!(events.try_recv().unwrap().changes, vec![(1, Some(1000))]);
+        drop(token);
+        assert_eq!(cache.put(3, 300), Ok(()));
+        assert_eq!(cache.flush(), Ok(3));
+        let published = events.try_recv().unwrap();
+        assert_eq!(published.generation, 3);
+        assert_eq!(published.changes, vec![(3, Some(300))]);
+
+        // Empty flushes still announce the generation
+        assert_eq!(cache.flush(), Ok(4));
+        assert_eq!(events.try_recv().unwrap().changes, vec![]);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let cache = GreenBlueCache::with_capacity(16);
+        assert_eq!(cache.put(1, 100), Ok(()));
+        assert_eq!(cache.bulk_load().err(), Some(CacheError::CannotWrite));
+        assert_eq!(cache.flush(), Ok(1));
+
+        let mut load = cache.bulk_load().unwrap();
+        assert_eq!(load.insert(2, 200), Ok(()));
+        assert_eq!(load.insert(3, 300), Ok(()));
+        assert_eq!(cache.put(4, 400), Err(CacheError::CannotWrite));
+        assert_eq!(cache.flush(), Err(CacheError::CannotSwitch));
+        assert_eq!(vec![Some(100), None], cache.get(&[1, 2]));
+
+        // A wrong trailer keeps the current items and restores the other map
+        assert_eq!(load.commit(2, 0), Err(CacheError::LoadMismatch));
+        assert_eq!(cache.put(4, 400), Ok(()));
+        assert_eq!(cache.flush(), Ok(2));
+        assert_eq!(cache.flush(), Ok(3));
+        assert_eq!(vec![Some(100), None, Some(400)], cache.get(&[1, 2, 4]));
+
+        let mut expected = Checksum::default();
+        let mut load = cache.bulk_load().unwrap();
+        for (k, v) in [(2, 200), (3, 300)] {
+            expected.add(&k, &v);
+            assert_eq!(load.insert(k, v), Ok(()));
+        }
+        let generation = cache.generation();
+        assert_eq!(load.commit(expected.count(), expected.sum()), Ok(generation + 1));
+        assert_eq!(vec![None, Some(200), Some(300), None], cache.get(&[1, 2, 3, 4]));
+
+        // Both maps hold the loaded items
+        assert_eq!(cache.put(5, 500), Ok(()));
+        assert_eq!(cache.flush(), Ok(5));
+        assert_eq!(cache.flush(), Ok(6));
+        assert_eq!(vec![None, Some(200), Some(500)], cache.get(&[1, 2, 5]));
+        assert_eq!(cache.len(), 3);
     }
 }
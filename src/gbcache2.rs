@@ -1,17 +1,27 @@
-use std::borrow::{BorrowMut, Borrow};
 /// Green-Blue Cache
 /// 
 /// 
 use dashmap::DashMap;
-use std::fmt::Display;
+use std::borrow::Borrow;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::SystemTime;
 use tokio::time::Duration;
 
-pub type Result<T> = std::result::Result<T, CacheError>;
+use crate::cache::{CacheReader, CacheStatus, CacheWriter, MapStatus};
+pub use crate::error::{CacheError, Result};
+use crate::quiesce::wait_until;
+pub use crate::quiesce::DRAIN_TIMEOUT;
 
-const THROTTLE: Duration = Duration::from_nanos(1);
+/// References to the write map not held by readers: the `green`/`blue`
+/// field, `refs.write` and the one taken by `flush` itself.
+const OWNED_REFS: usize = 3;
+
+/// References to a map owned by the cache between flushes: the `green`/`blue`
+/// field and `refs.read` or `refs.write`.
+const HELD_REFS: usize = 2;
 
 #[derive(Debug)]
 pub struct GreenBlueCache<K, V>
@@ -20,6 +30,12 @@ where K: Eq + Hash + Sized {
     blue:  Arc<DashMap<K, V>>,
     pending: Arc<RwLock<Vec<(K, V)>>>,
     refs: Arc<RwLock<ReadWriteRef<K, V>>>,
+    /// The write map still had readers when the last flush timed out and is
+    /// missing the pending log. Only changed while holding `pending`.
+    stale: AtomicBool,
+    drain_timeout: Duration,
+    generation: AtomicU64,
+    last_flush: RwLock<Option<SystemTime>>,
 }
 
 #[derive(Debug)]
@@ -29,22 +45,6 @@ where K: Eq + Hash + Sized {
     write: Arc<DashMap<K, V>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum CacheError {
-    NotFound,
-    CannotSwitch,
-    CannotWrite,
-}
-
-impl std::fmt::Display for CacheError {
-    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-         formatter.write_str(&format!("{:?}", self))?;
-         Ok(())
-    }
-}
-
-impl std::error::Error for CacheError {}
-
 impl<K, V> Default for GreenBlueCache<K, V> 
 where K: Eq + Hash + Sized {
     fn default() -> Self {
@@ -60,6 +60,10 @@ where K: Eq + Hash + Sized {
                 write,
             })),
             pending: Arc::new(RwLock::new(Vec::new())),
+            stale: AtomicBool::new(false),
+            drain_timeout: DRAIN_TIMEOUT,
+            generation: AtomicU64::new(0),
+            last_flush: RwLock::new(None),
         }
     }
 }
@@ -67,24 +71,72 @@ where K: Eq + Hash + Sized {
 
 impl<K, V> GreenBlueCache<K, V>
 where 
-    K: Eq + Hash + Sized + Clone + Display,
-    V: Clone + Display {
+    K: Eq + Hash + Sized + Clone,
+    V: Clone {
+
+    /// Sets how long `flush` waits for readers to leave the retired map.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
 
     pub fn put(&self, key: K, value: V) -> Result<()> {
         let mut pending = self.pending.write().unwrap();
-        let cache = self.refs.clone().read().unwrap().write.clone();
-        pending.push((key.clone(), value.clone()));
-        cache.insert(key, value);
+        if !self.stale.load(Ordering::Relaxed) {
+            let cache = self.refs.clone().read().unwrap().write.clone();
+            cache.insert(key.clone(), value.clone());
+        }
+        pending.push((key, value));
         Ok(())
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
+    pub fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        let cache = self.refs.clone().read().unwrap().read.clone();
+        keys.into_iter()
+            .map(|k| cache.get(k).map(|v| v.clone()))
+            .collect()
+    }
+
+    /// Visits the values of `keys` without cloning them, see
+    /// `CacheReader::get_with`.
+    pub fn get_with<'q, Q, I, F>(&self, keys: I, mut f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
         let cache = self.refs.clone().read().unwrap().read.clone();
-        cache.get(key).map(|v| v.clone())
+        for k in keys {
+            f(k, cache.get(k).as_deref());
+        }
     }
 
-    pub fn flush(&self) -> Result<()> {
+    /// Publishes the pending writes, see `gbcache::GreenBlueCache::flush`
+    /// for the guarantees and the `CannotSwitch` timeout.
+    ///
+    /// Readers hold a clone of the read map while they use it, so the
+    /// retired map is drained once only the references owned by the cache
+    /// are left.
+    pub fn flush(&self) -> Result<u64> {
         let mut pending = self.pending.write().unwrap();
+
+        if self.stale.load(Ordering::Relaxed) {
+            let cache = self.refs.clone().read().unwrap().write.clone();
+            if !wait_until(self.drain_timeout, || Arc::strong_count(&cache) <= OWNED_REFS) {
+                return Err(CacheError::CannotSwitch);
+            }
+            for (k, v) in pending.iter() {
+                cache.insert(k.clone(), v.clone());
+            }
+            self.stale.store(false, Ordering::Relaxed);
+        }
+
         {
             let rc = self.refs.clone();
             let mut refs = rc.write().unwrap();
@@ -94,16 +146,15 @@ where
             refs.write = read;
         }
         // From now on new readers will use the new cache
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        *self.last_flush.write().unwrap() = Some(SystemTime::now());
 
         // Wait for readers on the old map to finish
-        //println!("** flush: wait readers rc={:?}", Arc::strong_count(&refs.write));
         let cache = self.refs.clone().read().unwrap().write.clone();
-        while Arc::strong_count(&cache) > 3 {
-            std::thread::sleep(THROTTLE);
+        if !wait_until(self.drain_timeout, || Arc::strong_count(&cache) <= OWNED_REFS) {
+            self.stale.store(true, Ordering::Relaxed);
+            return Err(CacheError::CannotSwitch);
         }
-        // assert_eq!(Arc::strong_count(&self.caches[i]), 1);
-        //println!("** flush: DONE wait readers");
-        // TODO
 
         // Insert pending items in inactive cache
         for (k, v) in pending.iter() {
@@ -111,18 +162,72 @@ where
         }
         pending.clear();
 
-        Ok(())
+        Ok(generation)
+    }
+
+    pub fn status(&self) -> CacheStatus {
+        let current = if Arc::ptr_eq(&self.refs.read().unwrap().read, &self.green) {
+            0
+        } else {
+            1
+        };
+        CacheStatus {
+            maps: [&self.green, &self.blue]
+                .iter()
+                .map(|map| MapStatus {
+                    items: map.len(),
+                    shards: map.shards().len(),
+                    readers: Arc::strong_count(map).saturating_sub(HELD_REFS),
+                })
+                .collect(),
+            pending: self.pending.read().unwrap().len(),
+            current,
+            generation: self.generation.load(Ordering::SeqCst),
+            last_flush: *self.last_flush.read().unwrap(),
+        }
+    }
+
+}
+
+impl<K, V> CacheReader<K, V> for GreenBlueCache<K, V>
+where
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
+{
+    fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        GreenBlueCache::get(self, keys)
+    }
+
+    fn get_with<'q, Q, I, F>(&self, keys: I, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        GreenBlueCache::get_with(self, keys, f)
+    }
+}
+
+impl<K, V> CacheWriter<K, V> for GreenBlueCache<K, V>
+where
+    K: Eq + Hash + Sized + Clone,
+    V: Clone,
+{
+    fn put(&self, key: K, value: V) -> Result<()> {
+        GreenBlueCache::put(self, key, value)
     }
 
-    pub fn status(&self) {
-        println!("************ Green: {}_items {}_readers // Blue: {}_items {}_readers // Pending: {} Read: {}",
-            self.green.len(),
-            Arc::strong_count(&self.green),
-            self.blue.len(),
-            Arc::strong_count(&self.blue),
-            self.pending.read().unwrap().len(),
-            self.refs.read().unwrap().read.len(),
-        );
+    fn flush(&self) -> Result<u64> {
+        GreenBlueCache::flush(self)
     }
 
-}
\ No newline at end of file
+    fn status(&self) -> CacheStatus {
+        GreenBlueCache::status(self)
+    }
+}
@@ -0,0 +1,152 @@
+/// Key distributions for the benchmark readers
+///
+/// Keys are drawn as integers, `1..=keys` were written by the benchmark and
+/// larger ones are guaranteed misses.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::Zipf;
+use serde::{Deserialize, Serialize};
+
+use crate::workload::BenchConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Distribution {
+    /// Every key is equally likely.
+    Uniform,
+    /// Key `n` is drawn with probability proportional to `1 / n^zipf_exponent`.
+    Zipfian,
+    /// `hot_ratio` of the reads go to the first `hot_fraction` of the keys.
+    Hotspot,
+    /// Keys in order from a random start, wrapping around.
+    Sequential,
+    /// Zipfian over the distance to the last key written.
+    Latest,
+}
+
+/// Last key written by the benchmark writer, shared with the readers.
+pub type Latest = Arc<AtomicUsize>;
+
+pub struct KeyGen {
+    rng: StdRng,
+    distribution: Distribution,
+    keys: usize,
+    zipf: Option<Zipf<f64>>,
+    hot_keys: usize,
+    hot_ratio: f64,
+    miss_ratio: f64,
+    next: usize,
+    latest: Latest,
+}
+
+impl KeyGen {
+    /// Generator of reader `stream`, runs with the same seed and stream draw
+    /// the same keys.
+    pub fn new(config: &BenchConfig, stream: usize, latest: Latest) -> Self {
+        let keys = config.write_iters.max(1);
+        let mut rng = StdRng::seed_from_u64(config.seed.wrapping_add(stream as u64));
+        let zipf = match config.distribution {
+            Distribution::Zipfian | Distribution::Latest => Some(
+                Zipf::new(keys as u64, config.zipf_exponent).expect("validated zipf_exponent"),
+            ),
+            _ => None,
+        };
+        Self {
+            next: rng.gen_range(0..keys),
+            rng,
+            distribution: config.distribution,
+            keys,
+            zipf,
+            hot_keys: ((keys as f64 * config.hot_fraction) as usize).clamp(1, keys),
+            hot_ratio: config.hot_ratio,
+            miss_ratio: config.miss_ratio,
+            latest,
+        }
+    }
+
+    pub fn next_key(&mut self) -> usize {
+        if self.miss_ratio > 0.0 && self.rng.gen_bool(self.miss_ratio) {
+            return self.keys + self.rng.gen_range(1..=self.keys);
+        }
+        match self.distribution {
+            Distribution::Uniform => self.rng.gen_range(1..=self.keys),
+            Distribution::Zipfian => self.zipf(),
+            Distribution::Hotspot => {
+                if self.hot_keys == self.keys || self.rng.gen_bool(self.hot_ratio) {
+                    self.rng.gen_range(1..=self.hot_keys)
+                } else {
+                    self.rng.gen_range(self.hot_keys + 1..=self.keys)
+                }
+            }
+            Distribution::Sequential => {
+                self.next = (self.next + 1) % self.keys;
+                self.next + 1
+            }
+            Distribution::Latest => {
+                let latest = self.latest.load(Ordering::Relaxed).clamp(1, self.keys);
+                let offset = self.zipf() - 1;
+                (latest - 1 + self.keys - offset) % self.keys + 1
+            }
+        }
+    }
+
+    /// Rank in `1..=keys`, 1 being the most frequent.
+    fn zipf(&mut self) -> usize {
+        let zipf = self.zipf.expect("zipf distribution");
+        (self.rng.sample(zipf) as usize).clamp(1, self.keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(config: &BenchConfig, latest: &Latest, n: usize) -> Vec<usize> {
+        let mut keys = KeyGen::new(config, 0, latest.clone());
+        (0..n).map(|_| keys.next_key()).collect()
+    }
+
+    #[test]
+    fn test_distributions() {
+        let latest = Latest::default();
+        let mut config = BenchConfig {
+            write_iters: 1000,
+            seed: 42,
+            ..Default::default()
+        };
+        let uniform = draw(&config, &latest, 10_000);
+        assert_eq!(uniform, draw(&config, &latest, 10_000));
+        assert!(uniform.iter().all(|k| (1..=1000).contains(k)));
+
+        config.distribution = Distribution::Zipfian;
+        let zipf = draw(&config, &latest, 10_000);
+        let top = zipf.iter().filter(|&&k| k <= 10).count();
+        assert!(top > 3_000, "top 1% of keys drew {}", top);
+
+        config.distribution = Distribution::Hotspot;
+        let hot = draw(&config, &latest, 10_000);
+        let top = hot.iter().filter(|&&k| k <= 200).count();
+        assert!((7_500..8_500).contains(&top), "hot keys drew {}", top);
+
+        config.distribution = Distribution::Sequential;
+        let seq = draw(&config, &latest, 1001);
+        assert_eq!(seq[1], seq[0] % 1000 + 1);
+        assert_eq!(seq[1000], seq[0]);
+
+        config.distribution = Distribution::Latest;
+        latest.store(500, Ordering::Relaxed);
+        let recent = draw(&config, &latest, 10_000);
+        let top = recent.iter().filter(|&&k| (491..=500).contains(&k)).count();
+        assert!(top > 3_000, "latest keys drew {}", top);
+
+        config.distribution = Distribution::Uniform;
+        config.miss_ratio = 0.25;
+        let misses = draw(&config, &latest, 10_000);
+        let misses = misses.iter().filter(|&&k| k > 1000).count();
+        assert!((2_000..3_000).contains(&misses), "misses {}", misses);
+    }
+}
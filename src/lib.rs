@@ -0,0 +1,29 @@
+//! Single writer / multiple reader caches.
+//!
+//! Every backend implements [`CacheReader`] and [`CacheWriter`] so services
+//! can pick one by configuration:
+//!
+//! * [`gbcache::GreenBlueCache`]: two `DashMap`s, readers on one while the
+//!   writer fills the other, swapped on `flush`.
+//! * [`gbcache2::GreenBlueCache`]: same idea with read/write references
+//!   swapped under a lock.
+//! * [`rwcache::RwCache`]: a single `DashMap`, writes are visible at once.
+//! * [`lrcache`]: a `left_right` map, writes are published on `flush`.
+pub mod cache;
+pub mod error;
+pub mod evict;
+pub mod gbcache;
+pub mod gbcache2;
+pub mod keygen;
+pub mod lrcache;
+pub mod metrics;
+pub mod prom;
+mod quiesce;
+pub mod rwcache;
+pub mod snapshot;
+pub mod ttl;
+pub mod wal;
+pub mod workload;
+
+pub use cache::{CacheReader, CacheStatus, CacheWriter, MapStatus, Published};
+pub use error::{CacheError, Result};
@@ -1,63 +1,348 @@
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-use left_right::{Absorb, ReadHandle, WriteHandle};
+use left_right::{Absorb, ReadGuard, ReadHandle, WriteHandle};
+use parking_lot::Mutex;
 
-struct AddOpp<K, V>(pub K, pub V);
+use crate::cache::{self, CacheStatus, MapStatus};
+use crate::error::Result;
+use crate::snapshot::{self, Codec};
+use crate::ttl::Expiring;
 
-impl<K, V> Absorb<AddOpp<K, V>> for HashMap<K, V>
+type Map<K, V> = HashMap<K, Expiring<V>>;
+
+enum Opp<K, V> {
+    Add(K, Expiring<V>),
+    Remove(K),
+    /// Removes the key if it is still expired at the given time when the
+    /// operation is applied, a later `Add` is kept.
+    Expire(K, SystemTime),
+    Clear,
+    ReplaceAll(Map<K, V>),
+}
+
+impl<K, V> Absorb<Opp<K, V>> for Map<K, V>
 where
     K: Eq + Hash + Clone,
     V: Clone,
 {
-    fn absorb_first(&mut self, operation: &mut AddOpp<K, V>, _: &Self) {
-        self.insert(operation.0.clone(), operation.1.clone());
+    fn absorb_first(&mut self, operation: &mut Opp<K, V>, _: &Self) {
+        match operation {
+            Opp::Add(k, v) => {
+                self.insert(k.clone(), v.clone());
+            }
+            Opp::Remove(k) => {
+                self.remove(k);
+            }
+            Opp::Expire(k, now) => {
+                if self.get(k).is_some_and(|v| v.is_expired(*now)) {
+                    self.remove(k);
+                }
+            }
+            Opp::Clear => self.clear(),
+            Opp::ReplaceAll(map) => *self = map.clone(),
+        }
     }
 
-    fn absorb_second(&mut self, operation: AddOpp<K, V>, _: &Self) {
-        self.insert(operation.0.clone(), operation.1.clone());
+    // Last copy of the operation, values can be moved instead of cloned.
+    fn absorb_second(&mut self, operation: Opp<K, V>, _: &Self) {
+        match operation {
+            Opp::Add(k, v) => {
+                self.insert(k, v);
+            }
+            Opp::Remove(k) => {
+                self.remove(&k);
+            }
+            Opp::Expire(k, now) => {
+                if self.get(&k).is_some_and(|v| v.is_expired(now)) {
+                    self.remove(&k);
+                }
+            }
+            Opp::Clear => self.clear(),
+            Opp::ReplaceAll(map) => *self = map,
+        }
     }
 
     fn drop_first(self: Box<Self>) {}
 
     fn sync_with(&mut self, first: &Self) {
-        println!("Initial sync!!!!");
         self.extend(first.iter().map(|(k, v)| (k.clone(), v.clone())));
     }
 }
 
-pub struct CacheWriter<K: Eq + Hash + Clone, V: Clone>(WriteHandle<HashMap<K, V>, AddOpp<K, V>>);
+struct Writer<K: Eq + Hash + Clone, V: Clone> {
+    handle: WriteHandle<Map<K, V>, Opp<K, V>>,
+    /// Time to live of the values written by `put`.
+    default_ttl: Option<Duration>,
+    pending: usize,
+    /// Keys whose removal `expire` queued since the last flush.
+    expiring: HashSet<K>,
+    generation: u64,
+    last_flush: Option<SystemTime>,
+}
+
+/// The `left_right` write handle needs exclusive access, it is kept behind a
+/// mutex so the writer can be shared like the other backends.
+pub struct CacheWriter<K: Eq + Hash + Clone, V: Clone>(Mutex<Writer<K, V>>);
 impl<K, V> CacheWriter<K, V>
 where
     K: Eq + Hash + Clone,
     V: Clone,
 {
-    pub fn put(&mut self, k: K, v: V) {
-        self.0.append(AddOpp(k, v));
+    fn append(&self, op: Opp<K, V>) {
+        let mut w = self.0.lock();
+        w.handle.append(op);
+        w.pending += 1;
+    }
+
+    /// Sets the time to live of the values written by `put` and
+    /// `replace_all`.
+    pub fn default_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.0.get_mut().default_ttl = ttl;
+        self
+    }
+
+    pub fn put(&self, k: K, v: V) {
+        let mut w = self.0.lock();
+        let v = Expiring::new(v, w.default_ttl);
+        w.handle.append(Opp::Add(k, v));
+        w.pending += 1;
+    }
+
+    /// Writes `v` expiring `ttl` from now instead of the default.
+    pub fn put_with_ttl(&self, k: K, v: V, ttl: Duration) {
+        self.put_expiring(k, Expiring::new(v, Some(ttl)));
+    }
+
+    /// Writes a value with its expiry, e.g. replayed from a WAL.
+    pub fn put_expiring(&self, k: K, v: Expiring<V>) {
+        self.append(Opp::Add(k, v));
+    }
+
+    pub fn remove(&self, k: K) {
+        self.append(Opp::Remove(k));
+    }
+
+    /// Empties the cache on the next `flush`.
+    pub fn clear(&self) {
+        self.append(Opp::Clear);
+    }
+
+    /// Replaces the whole content of the cache with `map` on the next `flush`.
+    pub fn replace_all(&self, map: HashMap<K, V>) {
+        let mut w = self.0.lock();
+        let ttl = w.default_ttl;
+        let map = map
+            .into_iter()
+            .map(|(k, v)| (k, Expiring::new(v, ttl)))
+            .collect();
+        w.handle.append(Opp::ReplaceAll(map));
+        w.pending += 1;
+    }
+
+    /// Queues the removal of the published items that expired, returns how
+    /// many. Readers already skip them, the removals free them once flushed.
+    /// A key written again before the flush keeps its new value, a key
+    /// already queued is not queued again.
+    pub fn expire(&self) -> usize {
+        let mut w = self.0.lock();
+        let now = SystemTime::now();
+        let expired: Vec<K> = match w.handle.enter() {
+            Some(map) => map
+                .iter()
+                .filter(|(k, v)| v.is_expired(now) && !w.expiring.contains(*k))
+                .map(|(k, _)| k.clone())
+                .collect(),
+            None => return 0,
+        };
+        let n = expired.len();
+        for k in expired {
+            w.expiring.insert(k.clone());
+            w.handle.append(Opp::Expire(k, now));
+        }
+        w.pending += n;
+        n
+    }
+
+    /// Publishes the pending operations, returns the new generation.
+    pub fn flush(&self) -> u64 {
+        let mut w = self.0.lock();
+        w.handle.publish();
+        w.pending = 0;
+        w.expiring.clear();
+        w.generation += 1;
+        w.last_flush = Some(SystemTime::now());
+        w.generation
+    }
+
+    /// Saves the published items to `path` with their expiry, unpublished
+    /// writes and expired items are not included.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let w = self.0.lock();
+        let now = SystemTime::now();
+        let saved = match w.handle.enter() {
+            Some(map) => snapshot::save(path, map.iter().filter(|(_, v)| !v.is_expired(now))),
+            None => Err(io::Error::other("left-right map destroyed")),
+        };
+        saved
     }
 
-    pub fn flush(&mut self) {
-        self.0.publish();
+    /// Replaces the cache with a snapshot saved by `save_snapshot` and
+    /// publishes it, returns the number of items loaded. Items that expired
+    /// since the snapshot was saved are skipped.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let now = SystemTime::now();
+        let map: Map<K, V> = snapshot::load::<K, Expiring<V>>(path)?
+            .into_iter()
+            .filter(|(_, v)| !v.is_expired(now))
+            .collect();
+        let n = map.len();
+        self.append(Opp::ReplaceAll(map));
+        self.flush();
+        Ok(n)
+    }
+
+    pub fn status(&self) -> CacheStatus {
+        let w = self.0.lock();
+        let items = w.handle.enter().map(|m| m.len()).unwrap_or(0);
+        CacheStatus {
+            maps: vec![MapStatus {
+                items,
+                ..Default::default()
+            }],
+            pending: w.pending,
+            current: 0,
+            generation: w.generation,
+            last_flush: w.last_flush,
+        }
     }
 }
 
 #[derive(Clone)]
-pub struct CacheReader<K: Eq + Hash + Clone, V: Clone>(ReadHandle<HashMap<K, V>>);
+pub struct CacheReader<K: Eq + Hash + Clone, V: Clone>(ReadHandle<Map<K, V>>);
 impl<K, V> CacheReader<K, V>
 where
     K: Eq + Hash + Clone,
     V: Clone,
 {
-    pub fn get(&self, keys: &[K]) -> Vec<Option<V>> {
-        if let Some(guard) = self.0.enter() {
-            keys.iter()
-                .map(|k| guard.get(k).map(|v| v.clone()))
-                .collect()
+    pub fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        let keys = keys.into_iter();
+        if let Some(view) = self.read() {
+            keys.map(|k| view.get(k).cloned()).collect()
         } else {
             //TODO: Return err result
-            keys.iter().map(|_| None).collect()
+            keys.map(|_| None).collect()
         }
     }
+
+    /// Calls `f` with every key of the batch and its value, borrowed from the
+    /// map instead of cloned.
+    pub fn get_with<'q, Q, I, F>(&self, keys: I, mut f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        let view = self.read();
+        for k in keys {
+            f(k, view.as_ref().and_then(|view| view.get(k)));
+        }
+    }
+
+    /// Takes a left-right read guard on the published map, `None` once the
+    /// writer is dropped, see `ReadView`.
+    pub fn read(&self) -> Option<ReadView<'_, K, V>> {
+        let guard = self.0.enter()?;
+        // One clock reading for every lookup of the view
+        let now = SystemTime::now();
+        Some(ReadView { guard, now })
+    }
+}
+
+/// Published map of a left-right cache held by a read guard, see
+/// `CacheReader::read`.
+///
+/// Lookups take no lock and return references into the map, valid as long as
+/// the view. The writer's `flush` blocks until every guard on the map it
+/// retires is dropped, there is no timeout, so a view kept alive stalls the
+/// writer. Values are checked for expiry against the time the view was
+/// taken.
+pub struct ReadView<'a, K, V> {
+    guard: ReadGuard<'a, Map<K, V>>,
+    now: SystemTime,
+}
+
+impl<K: Eq + Hash, V> ReadView<'_, K, V> {
+    /// The live value of `key`, borrowed from the map.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.guard.get(key).and_then(|v| v.live(self.now))
+    }
+}
+
+impl<K, V> cache::CacheReader<K, V> for CacheReader<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        CacheReader::get(self, keys)
+    }
+
+    fn get_with<'q, Q, I, F>(&self, keys: I, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        CacheReader::get_with(self, keys, f)
+    }
+}
+
+impl<K, V> cache::CacheWriter<K, V> for CacheWriter<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn put(&self, key: K, value: V) -> Result<()> {
+        CacheWriter::put(self, key, value);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<u64> {
+        Ok(CacheWriter::flush(self))
+    }
+
+    fn status(&self) -> CacheStatus {
+        CacheWriter::status(self)
+    }
 }
 
 pub fn new<K, V>() -> (CacheWriter<K, V>, CacheReader<K, V>)
@@ -65,8 +350,15 @@ where
     K: Default + Eq + Hash + Clone,
     V: Default + Clone,
 {
-    let (write, read) = left_right::new::<HashMap<K, V>, AddOpp<K, V>>();
-    let w = CacheWriter(write);
+    let (write, read) = left_right::new::<Map<K, V>, Opp<K, V>>();
+    let w = CacheWriter(Mutex::new(Writer {
+        handle: write,
+        default_ttl: None,
+        pending: 0,
+        expiring: HashSet::new(),
+        generation: 0,
+        last_flush: None,
+    }));
     let r = CacheReader(read);
     (w, r)
 }
@@ -77,7 +369,7 @@ mod tests {
 
     #[test]
     fn test_write_read() {
-        let (mut w, r) = new();
+        let (w, r) = new();
 
         println!(">> Empty");
         assert_eq!(vec![None], r.get(&[1]));
@@ -128,4 +420,71 @@ mod tests {
         drop(w);
         assert_eq!(vec![None; 5], r.get(&[1, 2, 3, 4, 5]));
     }
+
+    #[test]
+    fn test_expire() {
+        let (w, r) = new();
+        let w = w.default_ttl(Some(Duration::from_millis(50)));
+        w.put(1, 100);
+        w.put_with_ttl(2, 200, Duration::from_secs(60));
+        w.put(3, 300);
+        w.flush();
+        assert_eq!(vec![Some(100), Some(200), Some(300)], r.get(&[1, 2, 3]));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(vec![None, Some(200), None], r.get(&[1, 2, 3]));
+
+        // The removal of 1 is queued after its new value, which is kept
+        w.put_with_ttl(1, 1000, Duration::from_secs(60));
+        assert_eq!(w.expire(), 2);
+        assert_eq!(w.expire(), 0);
+        w.flush();
+        assert_eq!(vec![Some(1000), Some(200), None], r.get(&[1, 2, 3]));
+        assert_eq!(w.status().items(), 2);
+    }
+
+    #[test]
+    fn test_remove_clear_replace() {
+        let (w, r) = new();
+        w.put(1, 100);
+        w.put(2, 200);
+        w.flush();
+
+        println!(">> Remove");
+        w.remove(1);
+        assert_eq!(vec![Some(100), Some(200)], r.get(&[1, 2]));
+        w.flush();
+        assert_eq!(vec![None, Some(200)], r.get(&[1, 2]));
+
+        println!(">> Flush");
+        w.flush();
+        assert_eq!(vec![None, Some(200)], r.get(&[1, 2]));
+
+        println!(">> ReplaceAll");
+        w.replace_all(HashMap::from([(3, 300), (4, 400)]));
+        w.put(5, 500);
+        w.flush();
+        assert_eq!(
+            vec![None, None, Some(300), Some(400), Some(500)],
+            r.get(&[1, 2, 3, 4, 5])
+        );
+
+        println!(">> Flush");
+        w.flush();
+        assert_eq!(
+            vec![None, None, Some(300), Some(400), Some(500)],
+            r.get(&[1, 2, 3, 4, 5])
+        );
+
+        println!(">> Clear");
+        w.clear();
+        w.put(6, 600);
+        assert_eq!(vec![Some(300), None], r.get(&[3, 6]));
+        w.flush();
+        assert_eq!(vec![None, Some(600)], r.get(&[3, 6]));
+
+        println!(">> Flush");
+        w.flush();
+        assert_eq!(vec![None, Some(600)], r.get(&[3, 6]));
+    }
 }
@@ -1,3 +1,4 @@
+use std::fmt;
 use tokio::time::Duration;
 
 #[derive(Default, Debug)]
@@ -11,6 +12,10 @@ pub struct Metrics {
     all_max: Duration,
     timeouts: usize,
     success: f64,
+    /// Batch latencies since the last `reset_window`.
+    latency: Histogram,
+    /// Batch latencies since the start.
+    total: Histogram,
 }
 
 // impl Default for Metrics {
@@ -27,16 +32,289 @@ impl Metrics {
     pub fn put(&mut self, requests: usize, duration: Duration, timeout: Duration) {
         self.batch_count = requests;
         self.batch_duration = duration;
-        self.batch_avg = self.batch_duration / self.batch_count as u32;
+        self.batch_avg = average(self.batch_duration, self.batch_count);
         if self.batch_duration > self.all_max {
             self.all_max = self.batch_duration;
         }
-        self.all_count += requests as usize;
+        self.all_count += requests;
         self.all_duration += duration;
-        self.all_avg = self.all_duration / self.all_count as u32;
+        self.all_avg = average(self.all_duration, self.all_count);
         if self.batch_duration > timeout {
-            self.timeouts += self.batch_count as usize;
+            self.timeouts += self.batch_count;
         }
         self.success = 100.0 * (1.0 - (self.timeouts as f64 / self.all_count as f64));
+        self.latency.record(duration);
+        self.total.record(duration);
+    }
+
+    /// Requests recorded since the start.
+    pub fn count(&self) -> usize {
+        self.all_count
+    }
+
+    /// Requests whose batch took longer than the timeout.
+    pub fn timeouts(&self) -> usize {
+        self.timeouts
+    }
+
+    /// Percentage of requests served within the timeout.
+    pub fn success(&self) -> f64 {
+        self.success
+    }
+
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    /// Batch latencies since the start, not affected by `reset_window`.
+    pub fn total_latency(&self) -> &Histogram {
+        &self.total
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        self.latency.percentiles()
+    }
+
+    /// Starts a new reporting window, the running totals are kept.
+    pub fn reset_window(&mut self) {
+        self.latency.reset();
+    }
+
+    /// Adds the counts of another reader, e.g. to report all readers at once.
+    pub fn merge(&mut self, other: &Metrics) {
+        if other.all_count == 0 {
+            return;
+        }
+        self.batch_count = other.batch_count;
+        self.batch_duration = other.batch_duration;
+        self.batch_avg = other.batch_avg;
+        self.all_max = self.all_max.max(other.all_max);
+        self.all_count += other.all_count;
+        self.all_duration += other.all_duration;
+        self.all_avg = average(self.all_duration, self.all_count);
+        self.timeouts += other.timeouts;
+        self.success = 100.0 * (1.0 - (self.timeouts as f64 / self.all_count as f64));
+        self.latency.merge(&other.latency);
+        self.total.merge(&other.total);
+    }
+}
+
+/// `total / count` without truncating `count`, zero for no requests.
+fn average(total: Duration, count: usize) -> Duration {
+    match count {
+        0 => Duration::ZERO,
+        n => Duration::from_nanos((total.as_nanos() / n as u128) as u64),
+    }
+}
+
+/// Sub-buckets per power of two, as bits: values are kept with < 1% error.
+const SUB_BITS: u32 = 7;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+/// Latencies are tracked up to 2^40ns (~18 minutes), larger ones are clamped.
+const MAX_BITS: u32 = 40;
+const BUCKETS: usize = (MAX_BITS - SUB_BITS + 2) as usize * SUB_BUCKETS;
+
+/// Log-linear latency histogram in nanoseconds, HDR style.
+///
+/// Values below `SUB_BUCKETS` are counted exactly, above that every power of
+/// two range is split in `SUB_BUCKETS` linear buckets.
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn index(v: u64) -> usize {
+        let v = v.min((1 << MAX_BITS) - 1);
+        if v < SUB_BUCKETS as u64 {
+            return v as usize;
+        }
+        let group = 64 - v.leading_zeros() - SUB_BITS;
+        group as usize * SUB_BUCKETS + (v >> (group - 1)) as usize - SUB_BUCKETS
+    }
+
+    /// Highest value counted in bucket `i`.
+    fn value(i: usize) -> u64 {
+        let (group, sub) = (i / SUB_BUCKETS, (i % SUB_BUCKETS) as u64);
+        if group == 0 {
+            return sub;
+        }
+        let shift = group as u32 - 1;
+        ((sub + SUB_BUCKETS as u64) << shift) + (1 << shift) - 1
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        self.record_n(duration, 1);
+    }
+
+    pub fn record_n(&mut self, duration: Duration, n: u64) {
+        let v = duration.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[Self::index(v)] += n;
+        self.count += n;
+        self.sum = self.sum.saturating_add(v.saturating_mul(n));
+        self.max = self.max.max(v);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Total of the recorded latencies.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum)
+    }
+
+    /// Number of recorded latencies up to `bound`, within bucket precision.
+    pub fn count_le(&self, bound: Duration) -> u64 {
+        let bound = bound.as_nanos().min(u64::MAX as u128) as u64;
+        let last = Self::index(bound);
+        let below: u64 = self.counts[..last].iter().sum();
+        if Self::value(last) <= bound {
+            below + self.counts[last]
+        } else {
+            below
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Latency at quantile `q` in `0.0..=1.0`, zero when empty.
+    pub fn quantile(&self, q: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                return Duration::from_nanos(Self::value(i).min(self.max));
+            }
+        }
+        self.max()
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            count: self.count,
+            p50: self.quantile(0.50),
+            p90: self.quantile(0.90),
+            p99: self.quantile(0.99),
+            p999: self.quantile(0.999),
+            max: self.max(),
+        }
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (c, o) in self.counts.iter_mut().zip(&other.counts) {
+            *c += o;
+        }
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.fill(0);
+        self.count = 0;
+        self.sum = 0;
+        self.max = 0;
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Histogram {{ {} }}", self.percentiles())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "count: {} p50: {:?} p90: {:?} p99: {:?} p99.9: {:?} max: {:?}",
+            self.count, self.p50, self.p90, self.p99, self.p999, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: u64, actual: Duration) {
+        let actual = actual.as_nanos() as f64;
+        assert!(
+            (actual - expected as f64).abs() <= expected as f64 / SUB_BUCKETS as f64,
+            "expected ~{}ns, got {}ns",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut h = Histogram::default();
+        for i in 1..=100_000 {
+            h.record(Duration::from_nanos(i * 10));
+        }
+        let p = h.percentiles();
+        assert_eq!(p.count, 100_000);
+        assert_close(500_000, p.p50);
+        assert_close(900_000, p.p90);
+        assert_close(990_000, p.p99);
+        assert_close(999_000, p.p999);
+        assert_eq!(p.max, Duration::from_nanos(1_000_000));
+
+        h.record(Duration::from_secs(3600));
+        assert_eq!(h.max(), Duration::from_secs(3600));
+        assert_close(1 << MAX_BITS, h.quantile(1.0));
+    }
+
+    #[test]
+    fn test_merge_reset() {
+        let mut a = Metrics::default();
+        let mut b = Metrics::default();
+        for i in 1..=100 {
+            a.put(10, Duration::from_micros(i), Duration::from_millis(1));
+            b.put(10, Duration::from_millis(i), Duration::from_millis(1));
+        }
+        a.merge(&b);
+        assert_eq!(a.all_count, 2000);
+        assert_eq!(a.timeouts, 990);
+        assert_eq!(a.latency().count(), 200);
+        assert_close(100_000, a.percentiles().p50);
+        assert_eq!(a.percentiles().max, Duration::from_millis(100));
+
+        a.reset_window();
+        assert_eq!(a.percentiles(), Percentiles::default());
+        assert_eq!(a.all_count, 2000);
+        assert_eq!(a.total_latency().count(), 200);
     }
 }
@@ -0,0 +1,150 @@
+/// Prometheus text exposition format
+///
+/// ```text
+/// # HELP cache_items Items per map
+/// # TYPE cache_items gauge
+/// cache_items{map="green"} 42
+/// ```
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::metrics::Histogram;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.000_01, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.05, 0.1, 1.0,
+];
+
+#[derive(Default)]
+pub struct Encoder {
+    out: String,
+}
+
+impl Encoder {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (k, v)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"", k);
+                escape_label(&mut self.out, v);
+                self.out.push('"');
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        self.sample(name, &[], value);
+    }
+
+    /// A gauge with one sample per set of label values, `values[_].0[i]` is
+    /// the value of `labels[i]`.
+    pub fn gauge_labeled<T: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        values: &[(Vec<&str>, T)],
+    ) {
+        self.labeled(name, help, "gauge", labels, values);
+    }
+
+    /// A counter with one sample per set of label values, see `gauge_labeled`.
+    pub fn counter_labeled(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        values: &[(Vec<&str>, u64)],
+    ) {
+        self.labeled(name, help, "counter", labels, values);
+    }
+
+    fn labeled<T: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        help: &str,
+        kind: &str,
+        labels: &[&str],
+        values: &[(Vec<&str>, T)],
+    ) {
+        self.header(name, help, kind);
+        for (l, v) in values {
+            let pairs: Vec<_> = labels.iter().copied().zip(l.iter().copied()).collect();
+            self.sample(name, &pairs, v);
+        }
+    }
+
+    /// A latency histogram in seconds with `LATENCY_BUCKETS` buckets.
+    pub fn histogram(&mut self, name: &str, help: &str, h: &Histogram) {
+        self.header(name, help, "histogram");
+        let bucket = format!("{}_bucket", name);
+        for le in LATENCY_BUCKETS {
+            let count = h.count_le(Duration::from_secs_f64(*le));
+            self.sample(&bucket, &[("le", &le.to_string())], count);
+        }
+        self.sample(&bucket, &[("le", "+Inf")], h.count());
+        self.sample(&format!("{}_sum", name), &[], h.sum().as_secs_f64());
+        self.sample(&format!("{}_count", name), &[], h.count());
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Appends a label value, escaping only what the text format requires.
+fn escape_label(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut h = Histogram::default();
+        h.record(Duration::from_micros(20));
+        h.record(Duration::from_millis(3));
+
+        let mut e = Encoder::default();
+        e.counter("cache_flush_total", "Flushes", 3);
+        let maps = [(vec!["green"], 1), (vec!["blue"], 2)];
+        e.gauge_labeled("cache_items", "Items per map", &["map"], &maps);
+        e.gauge_labeled("cache_pending", "Pending", &["table", "map"], &[(vec!["t", "green"], 4)]);
+        e.counter_labeled("cache_evictions_total", "Evictions", &["table"], &[(vec!["t"], 5)]);
+        e.gauge_labeled("cache_weight", "Weight", &["table"], &[(vec!["é'\"\\\n"], 6)]);
+        e.histogram("cache_read_seconds", "Read latency", &h);
+        let out = e.finish();
+
+        assert!(out.contains("# TYPE cache_flush_total counter\ncache_flush_total 3\n"));
+        assert!(out.contains("cache_items{map=\"green\"} 1\ncache_items{map=\"blue\"} 2\n"));
+        assert!(out.contains("cache_pending{table=\"t\",map=\"green\"} 4\n"));
+        assert!(out.contains("# TYPE cache_evictions_total counter\ncache_evictions_total{table=\"t\"} 5\n"));
+        assert!(out.contains("cache_weight{table=\"é'\\\"\\\\\\n\"} 6\n"));
+        assert!(out.contains("cache_read_seconds_bucket{le=\"0.00001\"} 0\n"));
+        assert!(out.contains("cache_read_seconds_bucket{le=\"0.00005\"} 1\n"));
+        assert!(out.contains("cache_read_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(out.contains("cache_read_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("cache_read_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,90 @@
+/// Reader quiescence for the green/blue caches
+///
+/// Readers register on the side they read from before touching it, so the
+/// writer can publish the other side and then wait until the retired side has
+/// no readers left before writing to it again.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default bound on how long `flush` waits for readers of the retired map.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Busy spins before `wait_until` starts yielding the thread.
+const SPINS: u32 = 128;
+
+#[derive(Debug, Default)]
+pub(crate) struct ReaderGate {
+    current: AtomicUsize,
+    readers: [AtomicUsize; 2],
+}
+
+/// Registration of a reader on one side, released on drop.
+pub(crate) struct ReadToken<'a> {
+    gate: &'a ReaderGate,
+    index: usize,
+}
+
+impl ReaderGate {
+    /// Side readers are currently sent to.
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    /// Number of readers registered on side `i`.
+    pub fn readers(&self, i: usize) -> usize {
+        self.readers[i].load(Ordering::SeqCst)
+    }
+
+    pub fn enter(&self) -> ReadToken<'_> {
+        loop {
+            let i = self.current.load(Ordering::SeqCst);
+            self.readers[i].fetch_add(1, Ordering::SeqCst);
+            // Either the writer sees our registration when it waits on `i`,
+            // or we see its switch here and retry on the other side.
+            if self.current.load(Ordering::SeqCst) == i {
+                return ReadToken { gate: self, index: i };
+            }
+            self.readers[i].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Sends new readers to the other side, returns the retired side.
+    pub fn switch(&self) -> usize {
+        self.current.fetch_xor(1, Ordering::SeqCst)
+    }
+
+    /// Waits until side `i` has no readers, `false` if `timeout` elapsed first.
+    pub fn wait_drained(&self, i: usize, timeout: Duration) -> bool {
+        wait_until(timeout, || self.readers(i) == 0)
+    }
+}
+
+impl ReadToken<'_> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for ReadToken<'_> {
+    fn drop(&mut self) {
+        self.gate.readers[self.index].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Spins, then yields, until `done` returns true or `timeout` elapses.
+pub(crate) fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut spins = 0;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        if spins < SPINS {
+            spins += 1;
+            std::hint::spin_loop();
+        } else {
+            std::thread::yield_now();
+        }
+    }
+    true
+}
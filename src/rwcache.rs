@@ -2,15 +2,16 @@
 ///
 ///
 use dashmap::DashMap;
-use std::borrow::{Borrow, BorrowMut};
-use std::fmt::Display;
+use std::borrow::Borrow;
 use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
 
-pub type Result<T> = std::result::Result<T, CacheError>;
-
-const THROTTLE: Duration = Duration::from_nanos(1);
+use crate::cache::{CacheReader, CacheStatus, CacheWriter, MapStatus};
+pub use crate::error::{CacheError, Result};
+use crate::snapshot::{self, Codec};
 
 #[derive(Debug)]
 pub struct RwCache<K, V>
@@ -18,27 +19,10 @@ where
     K: Eq + Hash + Sized,
 {
     cache: Arc<DashMap<K, V>>,
+    /// Number of `flush` calls.
+    generation: AtomicU64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum CacheError {
-    NotFound,
-    CannotSwitch,
-    CannotWrite,
-}
-
-impl std::fmt::Display for CacheError {
-    fn fmt(
-        &self,
-        formatter: &mut std::fmt::Formatter<'_>,
-    ) -> std::result::Result<(), std::fmt::Error> {
-        formatter.write_str(&format!("{:?}", self))?;
-        Ok(())
-    }
-}
-
-impl std::error::Error for CacheError {}
-
 impl<K, V> Default for RwCache<K, V>
 where
     K: Eq + Hash + Sized,
@@ -46,14 +30,15 @@ where
     fn default() -> Self {
         Self {
             cache: Arc::new(DashMap::new()),
+            generation: AtomicU64::new(0),
         }
     }
 }
 
 impl<K, V> RwCache<K, V>
 where
-    K: Eq + Hash + Clone + Display,
-    V: Clone + Display,
+    K: Eq + Hash + Clone,
+    V: Clone,
 {
     pub fn put(&self, key: K, value: V) -> Result<()> {
         // println!("** put {}: {}", &key, &value);
@@ -62,20 +47,119 @@ where
         Ok(())
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
+    pub fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
         let cache = self.cache.clone();
         // println!("** get: current {}, readers {:?}", &key, Arc::strong_count(&rc));
-        let result = cache.get(key).map(|v| v.clone());
-        result
+        keys.into_iter()
+            .map(|k| cache.get(k).map(|v| v.clone()))
+            .collect()
+    }
+
+    /// Visits the values of `keys` without cloning them, see
+    /// `CacheReader::get_with`. Each value is read-locked while `f` runs.
+    pub fn get_with<'q, Q, I, F>(&self, keys: I, mut f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        for k in keys {
+            f(k, self.cache.get(k).as_deref());
+        }
+    }
+
+    /// Writes are visible as soon as `put` returns, there is nothing to
+    /// publish. Only counts the generation.
+    pub fn flush(&self) -> Result<u64> {
+        Ok(self.generation.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    /// Saves the cache to `path`, concurrent writes may or may not be included.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let mut w = snapshot::Writer::create(path)?;
+        for item in self.cache.iter() {
+            w.write(item.key(), item.value())?;
+        }
+        w.finish()
+    }
+
+    /// Loads a snapshot saved by `save_snapshot`, returns the number of items loaded.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Codec,
+        V: Codec,
+    {
+        let items = snapshot::load::<K, V>(path)?;
+        let n = items.len();
+        for (k, v) in items {
+            self.cache.insert(k, v);
+        }
+        Ok(n)
+    }
+
+    pub fn status(&self) -> CacheStatus {
+        CacheStatus {
+            maps: vec![MapStatus {
+                items: self.cache.len(),
+                shards: self.cache.shards().len(),
+                readers: Arc::strong_count(&self.cache) - 1,
+            }],
+            generation: self.generation.load(Ordering::SeqCst),
+            ..Default::default()
+        }
+    }
+}
+
+impl<K, V> CacheReader<K, V> for RwCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn get<'q, Q, I>(&self, keys: I) -> Vec<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        RwCache::get(self, keys)
+    }
+
+    fn get_with<'q, Q, I, F>(&self, keys: I, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + 'q,
+        I: IntoIterator<Item = &'q Q>,
+        F: FnMut(&Q, Option<&V>),
+    {
+        RwCache::get_with(self, keys, f)
+    }
+}
+
+impl<K, V> CacheWriter<K, V> for RwCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn put(&self, key: K, value: V) -> Result<()> {
+        RwCache::put(self, key, value)
+    }
+
+    fn flush(&self) -> Result<u64> {
+        RwCache::flush(self)
     }
 
-    pub fn status(&self) {
-        println!(
-            "************ Cache: {}_items {}_readers {}_shards",
-            self.cache.len(),
-            Arc::strong_count(&self.cache),
-            self.cache.shards().len()
-        );
+    fn status(&self) -> CacheStatus {
+        RwCache::status(self)
     }
 }
 
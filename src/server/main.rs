@@ -0,0 +1,435 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use clap::{Parser, ValueEnum};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use grpc_cache::evict::Policy;
+use grpc_cache::gbcache;
+use grpc_cache::ttl::Expiring;
+use grpc_cache::wal::FsyncPolicy;
+use grpc_cache::CacheError;
+
+mod metrics;
+use metrics::ServerMetrics;
+mod tables;
+use tables::{Settings, TableConfig, Tables};
+mod watch;
+
+pub mod pb {
+    tonic::include_proto!("cache");
+}
+
+use pb::cache_server::{Cache, CacheServer};
+use pb::*;
+
+#[derive(Parser, Debug)]
+#[command(about = "gRPC server exposing named GreenBlueCache tables")]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:50051")]
+    addr: SocketAddr,
+
+    /// Directory of the table snapshots, rewritten after every flush without
+    /// --wal, and of the list of tables. Without it tables only live in
+    /// memory
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Also log the writes and flushes of every table, replayed on startup
+    #[arg(long, requires = "data_dir")]
+    wal: bool,
+
+    /// WAL fsync policy: always, never or an interval such as 100ms
+    #[arg(long, default_value = "always")]
+    wal_fsync: FsyncPolicy,
+
+    /// Size a table's WAL grows to before a flush saves its snapshot and
+    /// truncates the log
+    #[arg(long, default_value_t = 64 << 20)]
+    wal_max_bytes: u64,
+
+    /// Default initial capacity of each green/blue map of a table
+    #[arg(long, default_value_t = 1_000_000)]
+    capacity: usize,
+
+    /// Default number of flushes that can be rolled back in a row
+    #[arg(long, default_value_t = gbcache::HISTORY)]
+    history: usize,
+
+    /// Default time to live of the values put without one, in milliseconds
+    #[arg(long)]
+    default_ttl_ms: Option<u64>,
+
+    /// Default memory budget of a table, in bytes of keys and values
+    #[arg(long)]
+    memory_budget: Option<u64>,
+
+    /// What writes over the memory budget do by default
+    #[arg(long, value_enum, default_value_t = Policy::Lru)]
+    eviction: Policy,
+
+    /// How often expired values are queued for removal, in milliseconds, 0
+    /// leaves them until they are overwritten
+    #[arg(long, default_value_t = 1000)]
+    sweep_interval_ms: u64,
+
+    /// How long a bulk load waits for the next message before it is aborted,
+    /// in milliseconds, 0 waits forever
+    #[arg(long, default_value_t = 30_000)]
+    bulk_load_idle_ms: u64,
+
+    /// Address serving Prometheus metrics on /metrics
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+}
+
+struct CacheService {
+    tables: Arc<Tables>,
+    metrics: Arc<ServerMetrics>,
+    bulk_load_idle: Option<Duration>,
+}
+
+pub(crate) fn to_status(e: CacheError) -> Status {
+    match e {
+        CacheError::NotFound => Status::not_found(e.to_string()),
+        CacheError::CannotSwitch => Status::unavailable(e.to_string()),
+        CacheError::CannotWrite => Status::failed_precondition(e.to_string()),
+        CacheError::LoadMismatch => Status::data_loss(e.to_string()),
+        CacheError::GenerationNotReached => Status::unavailable(e.to_string()),
+    }
+}
+
+/// Next message of `stream`, fails with `DEADLINE_EXCEEDED` when none
+/// arrives within `idle`.
+async fn next_message<T>(
+    stream: &mut Streaming<T>,
+    idle: Option<Duration>,
+) -> Result<Option<T>, Status> {
+    match idle {
+        Some(idle) => tokio::time::timeout(idle, stream.message())
+            .await
+            .map_err(|_| Status::deadline_exceeded("no message within the idle timeout"))?,
+        None => stream.message().await,
+    }
+}
+
+/// Name of `policy` as taken by `--eviction` and `CreateTable`.
+fn policy_name(policy: Policy) -> String {
+    policy
+        .to_possible_value()
+        .map_or_else(String::new, |v| v.get_name().to_string())
+}
+
+#[tonic::async_trait]
+impl Cache for CacheService {
+    type WatchStream = watch::WatchStream;
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let start = Instant::now();
+        let GetRequest {
+            key,
+            min_generation,
+            table,
+        } = request.into_inner();
+        let table = self.tables.get(&table)?;
+        let (generation, mut values) = table
+            .cache
+            .get_at([key.as_str()], min_generation)
+            .map_err(to_status)?;
+        self.metrics.read(&values, start.elapsed());
+        Ok(Response::new(GetResponse {
+            value: values.pop().flatten(),
+            generation,
+        }))
+    }
+
+    async fn batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<Response<BatchGetResponse>, Status> {
+        let start = Instant::now();
+        let BatchGetRequest {
+            keys,
+            min_generation,
+            table,
+        } = request.into_inner();
+        let table = self.tables.get(&table)?;
+        let (generation, values) = table
+            .cache
+            .get_at(&keys, min_generation)
+            .map_err(to_status)?;
+        self.metrics.read(&values, start.elapsed());
+        let values = values.into_iter().map(|value| Value { value }).collect();
+        Ok(Response::new(BatchGetResponse { values, generation }))
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let PutRequest {
+            key,
+            value,
+            ttl_ms,
+            table,
+        } = request.into_inner();
+        let table = self.tables.get(&table)?;
+        let value = match ttl_ms {
+            0 => table.cache.expiring(value),
+            ms => Expiring::new(value, Some(Duration::from_millis(ms))),
+        };
+        // Waits for the WAL and a running flush
+        tokio::task::block_in_place(|| table.put(key, value))?;
+        self.metrics.put();
+        Ok(Response::new(PutResponse {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> Result<Response<RemoveResponse>, Status> {
+        let RemoveRequest { key, table } = request.into_inner();
+        let table = self.tables.get(&table)?;
+        tokio::task::block_in_place(|| table.remove(key))?;
+        self.metrics.remove();
+        Ok(Response::new(RemoveResponse {}))
+    }
+
+    async fn flush(
+        &self,
+        request: Request<FlushRequest>,
+    ) -> Result<Response<FlushResponse>, Status> {
+        let table = self.tables.get(&request.into_inner().table)?;
+        // Flushing replays the pending log, keep it off the async workers.
+        let metrics = self.metrics.clone();
+        let generation = tokio::task::spawn_blocking(move || {
+            // The flush is logged after the writes it publishes
+            let mut wal = table.wal.as_ref().map(|wal| wal.lock());
+            let start = Instant::now();
+            let flushed = table.cache.flush();
+            metrics.flush(start.elapsed(), flushed.is_ok());
+            let generation = flushed.map_err(to_status)?;
+            table.flushed(wal.as_mut())?;
+            Ok::<_, Status>(generation)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))??;
+        Ok(Response::new(FlushResponse { generation }))
+    }
+
+    async fn status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        let table = self.tables.get(&request.into_inner().table)?;
+        let status = table.cache.status();
+        Ok(Response::new(StatusResponse {
+            items: status.items() as u64,
+            pending: status.pending as u64,
+            current: status.current as u32,
+            generation: status.generation,
+            last_flush_ms: status
+                .last_flush
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as u64),
+            maps: status
+                .maps
+                .iter()
+                .map(|m| pb::MapStatus {
+                    items: m.items as u64,
+                    shards: m.shards as u32,
+                    readers: m.readers as u32,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let request = request.into_inner();
+        let table = self.tables.get(&request.table)?;
+        // Subscribe before returning so no flush after the call is missed
+        let flushes = table.cache.subscribe();
+        Ok(Response::new(watch::watch(request.into(), flushes)))
+    }
+
+    async fn bulk_load(
+        &self,
+        request: Request<Streaming<BulkLoadRequest>>,
+    ) -> Result<Response<BulkLoadResponse>, Status> {
+        let mut stream = request.into_inner();
+        let Some(first) = next_message(&mut stream, self.bulk_load_idle).await? else {
+            return Err(Status::invalid_argument("missing trailer"));
+        };
+        let table = self.tables.get(&first.table)?;
+        // Starting waits for the readers of the inactive map
+        let mut load = tokio::task::block_in_place(|| {
+            let _wal = table.wal.as_ref().map(|wal| wal.lock());
+            table.cache.bulk_load().map_err(to_status)
+        })?;
+        let mut trailer = None;
+        let mut next = Some(first);
+        while let Some(request) = next {
+            if trailer.is_some() {
+                return Err(Status::invalid_argument("entries after the trailer"));
+            }
+            for Entry { key, value } in request.entries {
+                load.insert(key, value).map_err(to_status)?;
+            }
+            trailer = request.trailer;
+            // An idle client would hold the table in the loading state
+            next = next_message(&mut stream, self.bulk_load_idle).await?;
+        }
+        let trailer = trailer.ok_or_else(|| Status::invalid_argument("missing trailer"))?;
+
+        // Copying the loaded items into the other map is O(items)
+        let count = load.count();
+        let generation = tokio::task::block_in_place(|| {
+            let mut wal = table.wal.as_ref().map(|wal| wal.lock());
+            let generation = load
+                .commit(trailer.count, trailer.checksum)
+                .map_err(to_status)?;
+            table.persist(wal.as_mut())?;
+            Ok::<_, Status>(generation)
+        })?;
+        println!(
+            "{}: bulk loaded {} items, generation {}",
+            table.name, count, generation
+        );
+        Ok(Response::new(BulkLoadResponse { count, generation }))
+    }
+
+    async fn rollback(
+        &self,
+        request: Request<RollbackRequest>,
+    ) -> Result<Response<RollbackResponse>, Status> {
+        let table = self.tables.get(&request.into_inner().table)?;
+        let name = table.name.clone();
+        let generation = tokio::task::spawn_blocking(move || {
+            let mut wal = table.wal.as_ref().map(|wal| wal.lock());
+            let generation = table.cache.rollback().map_err(to_status)?;
+            table.persist(wal.as_mut())?;
+            Ok::<_, Status>(generation)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))??;
+        println!("{}: rolled back to generation {}", name, generation);
+        Ok(Response::new(RollbackResponse { generation }))
+    }
+
+    async fn create_table(
+        &self,
+        request: Request<CreateTableRequest>,
+    ) -> Result<Response<CreateTableResponse>, Status> {
+        let CreateTableRequest {
+            name,
+            capacity,
+            default_ttl_ms,
+            history,
+            memory_budget,
+            eviction,
+        } = request.into_inner();
+        let eviction = eviction
+            .map(|e| Policy::from_str(&e, true))
+            .transpose()
+            .map_err(|_| Status::invalid_argument("eviction must be reject, lru, lfu or fifo"))?;
+        let config = self.tables.config(
+            capacity.map(|c| c as usize),
+            history.map(|h| h as usize),
+            default_ttl_ms,
+            memory_budget,
+            eviction,
+        );
+        let tables = self.tables.clone();
+        tokio::task::spawn_blocking(move || tables.create(&name, config))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))??;
+        Ok(Response::new(CreateTableResponse {}))
+    }
+
+    async fn drop_table(
+        &self,
+        request: Request<DropTableRequest>,
+    ) -> Result<Response<DropTableResponse>, Status> {
+        let name = request.into_inner().name;
+        let tables = self.tables.clone();
+        tokio::task::spawn_blocking(move || tables.drop_table(&name))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))??;
+        Ok(Response::new(DropTableResponse {}))
+    }
+
+    async fn list_tables(
+        &self,
+        _request: Request<ListTablesRequest>,
+    ) -> Result<Response<ListTablesResponse>, Status> {
+        let tables = self
+            .tables
+            .list()
+            .iter()
+            .map(|table| {
+                let status = table.cache.status();
+                TableInfo {
+                    name: table.name.clone(),
+                    capacity: table.config.capacity as u64,
+                    default_ttl_ms: table.config.default_ttl_ms,
+                    history: table.config.history as u32,
+                    items: status.items() as u64,
+                    pending: status.pending as u64,
+                    generation: status.generation,
+                    memory_budget: table.config.memory_budget,
+                    eviction: policy_name(table.config.eviction),
+                    weight: table.cache.weight().map(|w| w as u64),
+                    evictions: table.cache.evictions(),
+                }
+            })
+            .collect();
+        Ok(Response::new(ListTablesResponse { tables }))
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let settings = Settings {
+        data_dir: args.data_dir,
+        wal: args.wal,
+        wal_fsync: args.wal_fsync,
+        wal_max_bytes: args.wal_max_bytes,
+        sweep_interval: (args.sweep_interval_ms > 0)
+            .then(|| Duration::from_millis(args.sweep_interval_ms)),
+        defaults: TableConfig {
+            capacity: args.capacity,
+            history: args.history,
+            default_ttl_ms: args.default_ttl_ms,
+            memory_budget: args.memory_budget,
+            eviction: args.eviction,
+        },
+    };
+    let service = CacheService {
+        tables: Arc::new(Tables::open(settings)?),
+        metrics: Arc::default(),
+        bulk_load_idle: (args.bulk_load_idle_ms > 0)
+            .then(|| Duration::from_millis(args.bulk_load_idle_ms)),
+    };
+
+    if let Some(addr) = args.metrics_addr {
+        println!("metrics on http://{}/metrics", addr);
+        let serve = metrics::serve(addr, service.metrics.clone(), service.tables.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve.await {
+                eprintln!("metrics endpoint failed: {}", e);
+            }
+        });
+    }
+
+    println!("cache-server listening on {}", args.addr);
+    Server::builder()
+        .add_service(CacheServer::new(service))
+        .serve(args.addr)
+        .await?;
+
+    Ok(())
+}
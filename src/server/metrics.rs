@@ -0,0 +1,265 @@
+/// Server metrics and the Prometheus `/metrics` endpoint
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use grpc_cache::metrics::Histogram;
+use grpc_cache::{CacheStatus, MapStatus};
+use grpc_cache::prom::Encoder;
+
+use crate::tables::Tables;
+
+/// Reads are recorded per shard so readers on different threads do not
+/// contend, shards are merged when scraped. Only counts and a histogram, so
+/// recording never divides.
+#[derive(Default)]
+struct ReadShard {
+    latency: Histogram,
+    hits: u64,
+    misses: u64,
+}
+
+pub struct ServerMetrics {
+    reads: Vec<Mutex<ReadShard>>,
+    puts: AtomicU64,
+    removes: AtomicU64,
+    flushes: AtomicU64,
+    flush_errors: AtomicU64,
+    flush_latency: Mutex<Histogram>,
+}
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<usize> = Cell::new(NEXT_SHARD.fetch_add(1, Ordering::Relaxed));
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        let shards = std::thread::available_parallelism().map_or(8, |n| n.get());
+        Self {
+            reads: (0..shards).map(|_| Mutex::default()).collect(),
+            puts: AtomicU64::new(0),
+            removes: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+            flush_errors: AtomicU64::new(0),
+            flush_latency: Mutex::default(),
+        }
+    }
+}
+
+impl ServerMetrics {
+    pub fn read<V>(&self, values: &[Option<V>], duration: Duration) {
+        let hits = values.iter().filter(|v| v.is_some()).count();
+        let i = SHARD.with(|s| s.get()) % self.reads.len();
+        let mut shard = self.reads[i].lock();
+        shard.latency.record(duration);
+        shard.hits += hits as u64;
+        shard.misses += (values.len() - hits) as u64;
+    }
+
+    pub fn put(&self) {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self) {
+        self.removes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn flush(&self, duration: Duration, ok: bool) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.flush_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.flush_latency.lock().record(duration);
+    }
+
+    pub fn render(&self, tables: &Tables) -> String {
+        let mut reads = ReadShard::default();
+        for shard in &self.reads {
+            let shard = shard.lock();
+            reads.latency.merge(&shard.latency);
+            reads.hits += shard.hits;
+            reads.misses += shard.misses;
+        }
+
+        let tables: Vec<_> = tables.list();
+        let weights: Vec<_> = tables
+            .iter()
+            .filter_map(|t| Some((vec![t.name.as_str()], t.cache.weight()?)))
+            .collect();
+        let evictions: Vec<_> = tables
+            .iter()
+            .map(|t| (vec![t.name.as_str()], t.cache.evictions()))
+            .collect();
+        let tables: Vec<_> = tables
+            .iter()
+            .map(|t| (t.name.clone(), t.cache.status()))
+            .collect();
+        let per_map = |f: fn(&MapStatus) -> usize| -> Vec<(Vec<&str>, usize)> {
+            tables
+                .iter()
+                .flat_map(|(name, status)| {
+                    ["green", "blue"]
+                        .into_iter()
+                        .zip(&status.maps)
+                        .map(move |(map, m)| (vec![name.as_str(), map], f(m)))
+                })
+                .collect()
+        };
+        let per_table = |f: fn(&CacheStatus) -> u64| -> Vec<(Vec<&str>, u64)> {
+            tables
+                .iter()
+                .map(|(name, status)| (vec![name.as_str()], f(status)))
+                .collect()
+        };
+        let mut e = Encoder::default();
+        e.gauge_labeled(
+            "cache_items",
+            "Items per green/blue map",
+            &["table", "map"],
+            &per_map(|m| m.items),
+        );
+        e.gauge_labeled(
+            "cache_readers",
+            "Readers per green/blue map",
+            &["table", "map"],
+            &per_map(|m| m.readers),
+        );
+        e.gauge_labeled(
+            "cache_pending",
+            "Writes waiting for the next flush",
+            &["table"],
+            &per_table(|s| s.pending as u64),
+        );
+        e.gauge_labeled(
+            "cache_current_map",
+            "Map readers are served from, 0 is green",
+            &["table"],
+            &per_table(|s| s.current as u64),
+        );
+        e.gauge_labeled(
+            "cache_generation",
+            "Number of published flushes",
+            &["table"],
+            &per_table(|s| s.generation),
+        );
+        e.gauge_labeled(
+            "cache_weight_bytes",
+            "Bytes of keys and values of the tables with a memory budget",
+            &["table"],
+            &weights,
+        );
+        e.counter_labeled(
+            "cache_evictions_total",
+            "Items evicted to stay within the memory budget",
+            &["table"],
+            &evictions,
+        );
+        e.counter("cache_get_keys_total", "Keys looked up", reads.hits + reads.misses);
+        e.counter("cache_hits_total", "Keys found", reads.hits);
+        e.counter("cache_misses_total", "Keys not found", reads.misses);
+        e.counter("cache_puts_total", "Put requests", self.puts.load(Ordering::Relaxed));
+        e.counter("cache_removes_total", "Remove requests", self.removes.load(Ordering::Relaxed));
+        e.counter("cache_flushes_total", "Flush requests", self.flushes.load(Ordering::Relaxed));
+        e.counter(
+            "cache_flush_errors_total",
+            "Failed flush requests",
+            self.flush_errors.load(Ordering::Relaxed),
+        );
+        e.histogram(
+            "cache_read_duration_seconds",
+            "Get and BatchGet latency",
+            &reads.latency,
+        );
+        e.histogram(
+            "cache_flush_duration_seconds",
+            "Flush latency",
+            &self.flush_latency.lock(),
+        );
+        e.finish()
+    }
+}
+
+/// Serves `GET /metrics` until the listener fails.
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: Arc<ServerMetrics>,
+    tables: Arc<Tables>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        let tables = tables.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let n = stream.read(&mut buf).await?;
+            let response = if buf[..n].starts_with(b"GET /metrics ") {
+                let body = metrics.render(&tables);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+            stream.write_all(response.as_bytes()).await?;
+            stream.shutdown().await
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::{Settings, TableConfig};
+    use grpc_cache::wal::FsyncPolicy;
+
+    #[test]
+    fn test_render() {
+        let tables = Tables::open(Settings {
+            data_dir: None,
+            wal: false,
+            wal_fsync: FsyncPolicy::Always,
+            wal_max_bytes: 0,
+            sweep_interval: None,
+            defaults: TableConfig {
+                capacity: 16,
+                history: 1,
+                default_ttl_ms: None,
+                memory_budget: Some(1000),
+                eviction: Default::default(),
+            },
+        })
+        .unwrap();
+        let table = tables.get("").unwrap();
+        table.cache.put("k".to_string(), "v".into()).unwrap();
+        table.cache.flush().unwrap();
+
+        let metrics = ServerMetrics::default();
+        metrics.read(&[Some(1), None], Duration::from_micros(20));
+        metrics.read::<()>(&[], Duration::from_micros(20));
+        metrics.put();
+        metrics.flush(Duration::from_millis(1), false);
+        let out = metrics.render(&tables);
+
+        assert!(out.contains("cache_items{table=\"default\",map=\"green\"} 1\n"));
+        assert!(out.contains("cache_generation{table=\"default\"} 1\n"));
+        assert!(out.contains("cache_weight_bytes{table=\"default\"} 2\n"));
+        assert!(out.contains("cache_evictions_total{table=\"default\"} 0\n"));
+        assert!(out.contains("cache_get_keys_total 2\n"));
+        assert!(out.contains("cache_hits_total 1\n"));
+        assert!(out.contains("cache_puts_total 1\n"));
+        assert!(out.contains("cache_flush_errors_total 1\n"));
+        assert!(out.contains("cache_read_duration_seconds_count 2\n"));
+        assert!(out.contains("cache_flush_duration_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,498 @@
+/// Named tables, each with its own cache, pending log, WAL and snapshot
+///
+/// With a data directory every table persists to `<dir>/<name>.snap` and,
+/// with the WAL enabled, `<dir>/<name>.wal`. The tables and their settings
+/// are listed in `<dir>/tables.json` so they are reopened on startup.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tonic::Status;
+
+use grpc_cache::evict::Policy;
+use grpc_cache::gbcache::GreenBlueCache;
+use grpc_cache::ttl::{self, Expiring};
+use grpc_cache::wal::{FsyncPolicy, Record, Wal};
+use grpc_cache::CacheError;
+
+use crate::to_status;
+
+/// Table of the requests that do not name one, it always exists.
+pub const DEFAULT_TABLE: &str = "default";
+
+const MANIFEST: &str = "tables.json";
+
+/// Longest table name, names are also file names.
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TableConfig {
+    /// Initial capacity of each green/blue map.
+    pub capacity: usize,
+    /// Flushes that can be rolled back in a row.
+    pub history: usize,
+    /// Time to live of the values put without one.
+    pub default_ttl_ms: Option<u64>,
+    /// Bytes of keys and values kept, unlimited if `None`.
+    #[serde(default)]
+    pub memory_budget: Option<u64>,
+    /// Writes over the memory budget.
+    #[serde(default)]
+    pub eviction: Policy,
+}
+
+/// Server wide settings of the tables.
+pub struct Settings {
+    pub data_dir: Option<PathBuf>,
+    pub wal: bool,
+    pub wal_fsync: FsyncPolicy,
+    /// Size the WAL grows to before a flush saves the snapshot.
+    pub wal_max_bytes: u64,
+    /// How often expired values are queued for removal, `None` never.
+    pub sweep_interval: Option<Duration>,
+    /// Used for the default table and the settings a create leaves out.
+    pub defaults: TableConfig,
+}
+
+pub struct Table {
+    pub name: String,
+    pub config: TableConfig,
+    pub cache: Arc<GreenBlueCache<String, Bytes>>,
+    pub snapshot: Option<PathBuf>,
+    pub wal: Option<Arc<Mutex<Wal>>>,
+    wal_max_bytes: u64,
+    sweeper: Option<JoinHandle<()>>,
+}
+
+impl Table {
+    /// Opens the table, loading its snapshot and replaying its WAL if any.
+    fn open(name: &str, config: TableConfig, settings: &Settings) -> io::Result<Self> {
+        let mut cache = GreenBlueCache::with_capacity(config.capacity)
+            .history(config.history)
+            .default_ttl(config.default_ttl_ms.map(Duration::from_millis));
+        if let Some(budget) = config.memory_budget {
+            // Items weigh the bytes of their key and value
+            let weigh = |key: &String, value: &Bytes| key.len() + value.len();
+            cache = cache.memory_budget(budget as usize, config.eviction, weigh);
+        }
+        let snapshot = settings.data_dir.as_ref().map(|dir| dir.join(format!("{}.snap", name)));
+        if let Some(path) = snapshot.as_ref().filter(|p| p.exists()) {
+            let n = cache.load_snapshot(path)?;
+            println!("{}: loaded {} items from {}", name, n, path.display());
+        }
+        let wal = match &settings.data_dir {
+            Some(dir) if settings.wal => {
+                let path = dir.join(format!("{}.wal", name));
+                let (wal, records) = Wal::open::<String, Expiring<Bytes>>(&path, settings.wal_fsync)?;
+                println!("{}: replaying {} records from {}", name, records.len(), path.display());
+                // Evictions are not logged, replayed writes go through the
+                // memory budget again. Flushed writes are published again,
+                // the rest stays pending.
+                for record in records {
+                    match record {
+                        Record::Put(key, value) => cache.put_expiring(key, value),
+                        Record::Remove(key) => cache.remove(key),
+                        Record::Flush => cache.flush().map(drop),
+                    }
+                    .or_else(|e| match e {
+                        CacheError::CannotWrite => Ok(()),
+                        e => Err(e),
+                    })
+                    .map_err(io::Error::other)?;
+                }
+                Some(Arc::new(Mutex::new(wal)))
+            }
+            _ => None,
+        };
+
+        let cache = Arc::new(cache);
+        let sweeper = settings.sweep_interval.map(|interval| {
+            // Not logged to the WAL, expired values stay expired after a replay
+            let cache = cache.clone();
+            ttl::spawn_sweeper(interval, move || cache.expire())
+        });
+        Ok(Self {
+            name: name.to_string(),
+            config,
+            cache,
+            snapshot,
+            wal,
+            wal_max_bytes: settings.wal_max_bytes,
+            sweeper,
+        })
+    }
+
+    /// Puts a value, logged to the WAL if any once the cache accepted it
+    /// and before it is applied. Both happen under the WAL lock so the log
+    /// and the pending writes keep one order. A write the cache refuses,
+    /// e.g. during a load or over the memory budget, is not logged, and a
+    /// write the WAL fails to log is not applied: the log holds exactly the
+    /// acknowledged writes.
+    pub fn put(&self, key: String, value: Expiring<Bytes>) -> Result<(), Status> {
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock());
+        self.cache
+            .put_logged(key, value, |key, value| match &mut wal {
+                Some(wal) => wal.append_put(key, value),
+                None => Ok(()),
+            })
+            .map_err(to_status)?
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    /// Removes a key, logged like `put`.
+    pub fn remove(&self, key: String) -> Result<(), Status> {
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock());
+        self.cache
+            .remove_logged(key, |key| match &mut wal {
+                Some(wal) => wal.append_remove(key),
+                None => Ok(()),
+            })
+            .map_err(to_status)?
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    /// Persists a successful flush. With a WAL the flush is logged and the
+    /// snapshot only saved once the log outgrew `wal_max_bytes`, otherwise
+    /// every flush saves it. `wal` must be the locked WAL of the table, if
+    /// any.
+    pub fn flushed(&self, wal: Option<&mut MutexGuard<'_, Wal>>) -> Result<(), Status> {
+        let Some(wal) = wal else {
+            return self.persist(None);
+        };
+        let size = wal.append_flush().and_then(|()| wal.size());
+        if size.map_err(|e| Status::internal(e.to_string()))? < self.wal_max_bytes {
+            return Ok(());
+        }
+        self.persist(Some(wal))
+    }
+
+    /// Saves the published items and truncates the WAL they cover. `wal`
+    /// must be the locked WAL of the table, if any.
+    pub fn persist(&self, wal: Option<&mut MutexGuard<'_, Wal>>) -> Result<(), Status> {
+        if let Some(path) = &self.snapshot {
+            self.cache
+                .save_snapshot(path)
+                .map_err(|e| Status::internal(e.to_string()))?;
+            if let Some(wal) = wal {
+                wal.truncate().map_err(|e| Status::internal(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Some(sweeper) = &self.sweeper {
+            sweeper.abort();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    #[serde(flatten)]
+    config: TableConfig,
+}
+
+pub struct Tables {
+    tables: RwLock<HashMap<String, Arc<Table>>>,
+    settings: Settings,
+}
+
+impl Tables {
+    /// Opens the tables listed in the data directory, and the default table.
+    pub fn open(settings: Settings) -> io::Result<Self> {
+        let mut entries: Vec<Entry> = match &settings.data_dir {
+            Some(dir) if dir.join(MANIFEST).exists() => {
+                serde_json::from_slice(&fs::read(dir.join(MANIFEST))?)?
+            }
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        if !entries.iter().any(|e| e.name == DEFAULT_TABLE) {
+            entries.insert(
+                0,
+                Entry {
+                    name: DEFAULT_TABLE.to_string(),
+                    config: settings.defaults,
+                },
+            );
+        }
+        let mut tables = HashMap::new();
+        for Entry { name, config } in entries {
+            let table = Table::open(&name, config, &settings)?;
+            tables.insert(name, Arc::new(table));
+        }
+        let tables = Self {
+            tables: RwLock::new(tables),
+            settings,
+        };
+        tables.save_manifest(&tables.tables.read())?;
+        Ok(tables)
+    }
+
+    /// The table `name`, the default one if `name` is empty.
+    pub fn get(&self, name: &str) -> Result<Arc<Table>, Status> {
+        let name = if name.is_empty() { DEFAULT_TABLE } else { name };
+        self.tables
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("no table {:?}", name)))
+    }
+
+    /// Tables sorted by name.
+    pub fn list(&self) -> Vec<Arc<Table>> {
+        let mut tables: Vec<_> = self.tables.read().values().cloned().collect();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        tables
+    }
+
+    /// Settings of a new table, the server defaults where `None`.
+    pub fn config(
+        &self,
+        capacity: Option<usize>,
+        history: Option<usize>,
+        default_ttl_ms: Option<u64>,
+        memory_budget: Option<u64>,
+        eviction: Option<Policy>,
+    ) -> TableConfig {
+        let defaults = self.settings.defaults;
+        TableConfig {
+            capacity: capacity.unwrap_or(defaults.capacity),
+            history: history.unwrap_or(defaults.history),
+            default_ttl_ms: default_ttl_ms.or(defaults.default_ttl_ms),
+            memory_budget: memory_budget.or(defaults.memory_budget),
+            eviction: eviction.unwrap_or(defaults.eviction),
+        }
+    }
+
+    /// Creates an empty table, files left by a dropped table of the same
+    /// name are removed.
+    pub fn create(&self, name: &str, config: TableConfig) -> Result<Arc<Table>, Status> {
+        validate_name(name)?;
+        let mut tables = self.tables.write();
+        if tables.contains_key(name) {
+            return Err(Status::already_exists(format!("table {:?} exists", name)));
+        }
+        self.remove_files(name)?;
+        let table = Table::open(name, config, &self.settings)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let table = Arc::new(table);
+        tables.insert(name.to_string(), table.clone());
+        if let Err(e) = self.save_manifest(&tables) {
+            tables.remove(name);
+            return Err(Status::internal(e.to_string()));
+        }
+        println!("Created table {}", name);
+        Ok(table)
+    }
+
+    /// Drops a table and its files. Requests already holding it finish on
+    /// the dropped cache, watchers see their stream end.
+    pub fn drop_table(&self, name: &str) -> Result<(), Status> {
+        if name == DEFAULT_TABLE {
+            return Err(Status::failed_precondition("the default table can not be dropped"));
+        }
+        let mut tables = self.tables.write();
+        let table = tables
+            .remove(name)
+            .ok_or_else(|| Status::not_found(format!("no table {:?}", name)))?;
+        self.save_manifest(&tables)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        drop(tables);
+        // Wait for a flush saving the snapshot
+        let _wal = table.wal.as_ref().map(|wal| wal.lock());
+        self.remove_files(name)?;
+        println!("Dropped table {}", name);
+        Ok(())
+    }
+
+    fn remove_files(&self, name: &str) -> Result<(), Status> {
+        let Some(dir) = &self.settings.data_dir else {
+            return Ok(());
+        };
+        for ext in ["snap", "wal"] {
+            remove_file(&dir.join(format!("{}.{}", name, ext)))
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn save_manifest(&self, tables: &HashMap<String, Arc<Table>>) -> io::Result<()> {
+        let Some(dir) = &self.settings.data_dir else {
+            return Ok(());
+        };
+        let mut entries: Vec<_> = tables
+            .values()
+            .map(|t| Entry {
+                name: t.name.clone(),
+                config: t.config,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let tmp = dir.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_vec_pretty(&entries)?)?;
+        fs::rename(tmp, dir.join(MANIFEST))
+    }
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Names are 1 to `MAX_NAME_LEN` ASCII letters, digits, `-` or `_`.
+fn validate_name(name: &str) -> Result<(), Status> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(Status::invalid_argument(format!("invalid table name {:?}", name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    fn settings(dir: &Path) -> Settings {
+        Settings {
+            data_dir: Some(dir.to_path_buf()),
+            wal: true,
+            wal_fsync: FsyncPolicy::Never,
+            wal_max_bytes: 1 << 20,
+            sweep_interval: None,
+            defaults: TableConfig {
+                capacity: 16,
+                history: 1,
+                default_ttl_ms: None,
+                memory_budget: None,
+                eviction: Default::default(),
+            },
+        }
+    }
+
+    fn put(table: &Table, key: &str, value: &'static str) {
+        let value = Expiring::new(Bytes::from_static(value.as_bytes()), None);
+        table.put(key.to_string(), value).unwrap();
+    }
+
+    fn code<T>(result: Result<T, Status>) -> Code {
+        result.err().unwrap().code()
+    }
+
+    fn get(table: &Table, key: &str) -> Option<Bytes> {
+        table.cache.get([key]).pop().flatten()
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("a-b_1").is_ok());
+        assert!(validate_name(&"x".repeat(MAX_NAME_LEN)).is_ok());
+        for name in ["", "a/b", "..", "a b", &"x".repeat(MAX_NAME_LEN + 1)] {
+            assert_eq!(code(validate_name(name)), Code::InvalidArgument, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let tables = Tables::open(settings(dir.path())).unwrap();
+        let config =
+            tables.config(Some(32), Some(2), Some(1000), Some(1 << 20), Some(Policy::Fifo));
+        tables.create("t1", config).unwrap();
+        assert_eq!(code(tables.create("t1", config)), Code::AlreadyExists);
+        assert_eq!(code(tables.create("t/1", config)), Code::InvalidArgument);
+
+        let t1 = tables.get("t1").unwrap();
+        put(&t1, "k", "flushed");
+        t1.cache.flush().unwrap();
+        t1.flushed(Some(&mut t1.wal.as_ref().unwrap().lock())).unwrap();
+        // Only logged, the WAL is far from `wal_max_bytes`
+        assert!(!dir.path().join("t1.snap").exists());
+        put(&t1, "p", "pending");
+        drop(t1);
+        drop(tables);
+
+        // Reopened from the manifest with their settings, snapshot and WAL
+        let tables = Tables::open(settings(dir.path())).unwrap();
+        let names: Vec<_> = tables.list().iter().map(|t| t.name.clone()).collect();
+        assert_eq!(vec![DEFAULT_TABLE, "t1"], names);
+        let t1 = tables.get("t1").unwrap();
+        assert_eq!(t1.config, config);
+        assert_eq!(get(&t1, "k"), Some(Bytes::from_static(b"flushed")));
+        assert_eq!(t1.cache.generation(), 1);
+        assert_eq!(t1.cache.pending_len(), 1);
+        assert_eq!(tables.get("").unwrap().name, DEFAULT_TABLE);
+    }
+
+    #[test]
+    fn test_refused_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let tables = Tables::open(settings(dir.path())).unwrap();
+        let config = tables.config(None, None, None, Some(10), Some(Policy::Reject));
+        let t1 = tables.create("t1", config).unwrap();
+        put(&t1, "k", "12345");
+        let value = Expiring::new(Bytes::from_static(b"over budget"), None);
+        assert_eq!(code(t1.put("x".to_string(), value)), Code::FailedPrecondition);
+        t1.cache.flush().unwrap();
+        let load = t1.cache.bulk_load().unwrap();
+        assert_eq!(code(t1.remove("k".to_string())), Code::FailedPrecondition);
+        drop(load);
+        drop(t1);
+        drop(tables);
+
+        // Only the acknowledged write is replayed
+        let tables = Tables::open(settings(dir.path())).unwrap();
+        let t1 = tables.get("t1").unwrap();
+        assert_eq!(t1.cache.pending_len(), 1);
+        t1.cache.flush().unwrap();
+        assert_eq!(get(&t1, "k"), Some(Bytes::from_static(b"12345")));
+        assert_eq!(get(&t1, "x"), None);
+    }
+
+    #[test]
+    fn test_create_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let tables = Tables::open(settings(dir.path())).unwrap();
+        let config = tables.config(None, None, None, None, None);
+        let t1 = tables.create("t1", config).unwrap();
+        put(&t1, "k", "v");
+        t1.cache.flush().unwrap();
+        t1.persist(Some(&mut t1.wal.as_ref().unwrap().lock())).unwrap();
+        drop(t1);
+        assert!(dir.path().join("t1.snap").exists());
+
+        assert_eq!(code(tables.drop_table(DEFAULT_TABLE)), Code::FailedPrecondition);
+        assert_eq!(code(tables.drop_table("t2")), Code::NotFound);
+        tables.drop_table("t1").unwrap();
+        assert_eq!(code(tables.get("t1")), Code::NotFound);
+        assert!(!dir.path().join("t1.snap").exists());
+        assert!(!dir.path().join("t1.wal").exists());
+
+        // Files left behind by a dropped table are not loaded into a new one
+        fs::write(dir.path().join("t1.snap"), b"stale").unwrap();
+        fs::write(dir.path().join("t1.wal"), b"stale").unwrap();
+        let t1 = tables.create("t1", config).unwrap();
+        assert_eq!(get(&t1, "k"), None);
+        assert_eq!(t1.cache.pending_len(), 0);
+        assert!(!dir.path().join("t1.snap").exists());
+    }
+}
@@ -0,0 +1,94 @@
+/// Watch RPC: forwards the flushes published by the cache to one watcher
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+use grpc_cache::Published;
+
+use crate::pb::watch_event::Event;
+use crate::pb::{KeyChange, WatchEvent, WatchRequest};
+
+/// Events buffered for a watcher before its task waits for the client.
+const WATCH_BUFFER: usize = 1024;
+
+pub type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
+
+/// Keys a watcher asked for.
+pub struct Filter {
+    keys: HashSet<String>,
+    prefix: Option<String>,
+}
+
+impl From<WatchRequest> for Filter {
+    fn from(request: WatchRequest) -> Self {
+        Self {
+            keys: request.keys.into_iter().collect(),
+            prefix: request.prefix,
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, key: &str) -> bool {
+        self.keys.contains(key) || self.prefix.as_ref().is_some_and(|p| key.starts_with(p.as_str()))
+    }
+
+    /// The last change of every watched key in `published`, then its generation.
+    fn events(&self, published: &Published<String, Bytes>) -> Vec<WatchEvent> {
+        if published.replaced {
+            return vec![event(Event::Replaced(published.generation))];
+        }
+        let mut seen = HashSet::new();
+        let mut events: Vec<_> = published
+            .changes
+            .iter()
+            .rev()
+            .filter(|(key, _)| self.matches(key) && seen.insert(key.as_str()))
+            .map(|(key, value)| {
+                event(Event::Change(KeyChange {
+                    key: key.clone(),
+                    value: value.clone(),
+                }))
+            })
+            .collect();
+        events.reverse();
+        events.push(event(Event::Generation(published.generation)));
+        events
+    }
+}
+
+fn event(event: Event) -> WatchEvent {
+    WatchEvent { event: Some(event) }
+}
+
+/// Streams the flushes received on `flushes` that match `filter`, until the
+/// watcher disconnects.
+pub fn watch(
+    filter: Filter,
+    mut flushes: broadcast::Receiver<Arc<Published<String, Bytes>>>,
+) -> WatchStream {
+    let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+    tokio::spawn(async move {
+        loop {
+            let events = tokio::select! {
+                _ = tx.closed() => return,
+                received = flushes.recv() => match received {
+                    Ok(published) => filter.events(&published),
+                    Err(RecvError::Lagged(n)) => vec![event(Event::Lagged(n))],
+                    Err(RecvError::Closed) => return,
+                },
+            };
+            for e in events {
+                if tx.send(Ok(e)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
@@ -0,0 +1,326 @@
+/// Snapshot files
+///
+/// Layout, integers are little endian:
+///
+/// ```text
+/// magic "GBCS" | version: u16 | generation: u64
+/// count * (key_len: u32 | key | value_len: u32 | value)
+/// count: u64 | crc32 of everything above: u32
+/// ```
+///
+/// Snapshots are written to a temporary file renamed over `path` once
+/// complete, so a crash never leaves a truncated snapshot behind. Since
+/// version 2 the values of caches with TTL are `ttl::Expiring` values,
+/// version 1 snapshots still load with values that never expire. Version 3
+/// added the generation of the saved items, 0 when loading older snapshots.
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+const MAGIC: &[u8; 4] = b"GBCS";
+const VERSION: u16 = 3;
+const HEADER_LEN: usize = 4 + 2 + 8;
+/// Header of versions 1 and 2, without the generation.
+const V2_HEADER_LEN: usize = 4 + 2;
+const TRAILER_LEN: usize = 8 + 4;
+
+/// Binary encoding of snapshot keys and values.
+pub trait Codec: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(bytes: &[u8]) -> io::Result<Self>;
+
+    /// Decodes bytes written by `version` of a snapshot or WAL, for types
+    /// whose encoding changed since.
+    fn decode_version(bytes: &[u8], version: u16) -> io::Result<Self> {
+        let _ = version;
+        Self::decode(bytes)
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| invalid(&e.to_string()))
+    }
+}
+
+impl Codec for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec for Bytes {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        Ok(Bytes::copy_from_slice(bytes))
+    }
+}
+
+impl Codec for Arc<[u8]> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        Ok(Arc::from(bytes))
+    }
+}
+
+macro_rules! int_codec {
+    ($($t:ty),*) => {
+        $(impl Codec for $t {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> io::Result<Self> {
+                bytes
+                    .try_into()
+                    .map(<$t>::from_le_bytes)
+                    .map_err(|_| invalid("integer length"))
+            }
+        })*
+    };
+}
+
+int_codec!(u16, i32, i64, u32, u64);
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("snapshot: invalid {}", what))
+}
+
+/// Appends `value` prefixed with its encoded length.
+pub(crate) fn push_field(buf: &mut Vec<u8>, value: &impl Codec) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    value.encode(buf);
+    let len = (buf.len() - start - 4) as u32;
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Streams entries into a new snapshot.
+pub struct Writer {
+    path: PathBuf,
+    tmp: PathBuf,
+    out: BufWriter<File>,
+    crc: crc32fast::Hasher,
+    buf: Vec<u8>,
+    count: usize,
+}
+
+impl Writer {
+    /// Starts a snapshot at `path`, nothing is replaced until `finish`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::create_with_generation(path, 0)
+    }
+
+    /// Starts a snapshot of the items published at `generation`.
+    pub fn create_with_generation(path: impl AsRef<Path>, generation: u64) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let tmp = tmp_path(&path);
+        let mut w = Self {
+            out: BufWriter::new(File::create(&tmp)?),
+            path,
+            tmp,
+            crc: crc32fast::Hasher::new(),
+            buf: Vec::with_capacity(HEADER_LEN),
+            count: 0,
+        };
+        w.buf.extend_from_slice(MAGIC);
+        w.buf.extend_from_slice(&VERSION.to_le_bytes());
+        w.buf.extend_from_slice(&generation.to_le_bytes());
+        w.crc.update(&w.buf);
+        w.out.write_all(&w.buf)?;
+        Ok(w)
+    }
+
+    pub fn write<K: Codec, V: Codec>(&mut self, k: &K, v: &V) -> io::Result<()> {
+        self.buf.clear();
+        push_field(&mut self.buf, k);
+        push_field(&mut self.buf, v);
+        self.crc.update(&self.buf);
+        self.out.write_all(&self.buf)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Seals the snapshot and moves it over `path`, returns the entry count.
+    pub fn finish(mut self) -> io::Result<usize> {
+        let count = (self.count as u64).to_le_bytes();
+        self.crc.update(&count);
+        self.out.write_all(&count)?;
+        self.out.write_all(&self.crc.finalize().to_le_bytes())?;
+        self.out.into_inner()?.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        Ok(self.count)
+    }
+}
+
+/// Order independent checksum of a set of entries: the wrapping sum of the
+/// CRC32 of each entry in the snapshot encoding, used to verify bulk loads.
+#[derive(Debug, Default)]
+pub struct Checksum {
+    count: u64,
+    sum: u64,
+    buf: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn add<K: Codec, V: Codec>(&mut self, k: &K, v: &V) {
+        self.buf.clear();
+        push_field(&mut self.buf, k);
+        push_field(&mut self.buf, v);
+        self.sum = self.sum.wrapping_add(crc32fast::hash(&self.buf) as u64);
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+}
+
+/// Writes `entries` to `path`, returns the number of entries written.
+pub fn save<'a, K, V, I>(path: impl AsRef<Path>, entries: I) -> io::Result<usize>
+where
+    K: Codec + 'a,
+    V: Codec + 'a,
+    I: IntoIterator<Item = (&'a K, &'a V)>,
+{
+    let mut w = Writer::create(path)?;
+    for (k, v) in entries {
+        w.write(k, v)?;
+    }
+    w.finish()
+}
+
+/// Reads and verifies the snapshot at `path`.
+pub fn load<K: Codec, V: Codec>(path: impl AsRef<Path>) -> io::Result<Vec<(K, V)>> {
+    load_with_generation(path).map(|(_, entries)| entries)
+}
+
+/// Like `load`, also returns the generation the snapshot was written with.
+pub fn load_with_generation<K: Codec, V: Codec>(
+    path: impl AsRef<Path>,
+) -> io::Result<(u64, Vec<(K, V)>)> {
+    let data = fs::read(path)?;
+    if data.len() < V2_HEADER_LEN + TRAILER_LEN || &data[..4] != MAGIC {
+        return Err(invalid("header"));
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return Err(invalid("checksum"));
+    }
+    let version = u16::decode(&body[4..V2_HEADER_LEN])?;
+    if !(1..=VERSION).contains(&version) {
+        return Err(invalid("version"));
+    }
+    let (header_len, generation) = if version < 3 {
+        (V2_HEADER_LEN, 0)
+    } else if body.len() < HEADER_LEN + 8 {
+        return Err(invalid("header"));
+    } else {
+        (HEADER_LEN, u64::decode(&body[V2_HEADER_LEN..HEADER_LEN])?)
+    };
+    let (body, count) = body.split_at(body.len() - 8);
+    let count = u64::decode(count)? as usize;
+
+    let mut rest = &body[header_len..];
+    let mut field = || -> io::Result<&[u8]> {
+        if rest.len() < 4 {
+            return Err(invalid("entry"));
+        }
+        let len = u32::decode(&rest[..4])? as usize;
+        if rest.len() < 4 + len {
+            return Err(invalid("entry"));
+        }
+        let (f, r) = rest[4..].split_at(len);
+        rest = r;
+        Ok(f)
+    };
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let k = K::decode_version(field()?, version)?;
+        let v = V::decode_version(field()?, version)?;
+        entries.push((k, v));
+    }
+    if !rest.is_empty() {
+        return Err(invalid("entry count"));
+    }
+    Ok((generation, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snap");
+        let entries = vec![
+            ("1".to_string(), "@100".to_string()),
+            ("2".to_string(), String::new()),
+        ];
+
+        let n = save(&path, entries.iter().map(|(k, v)| (k, v))).unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(entries, load::<String, String>(&path).unwrap());
+
+        let mut w = Writer::create_with_generation(&path, 7).unwrap();
+        for (k, v) in &entries {
+            w.write(k, v).unwrap();
+        }
+        assert_eq!(w.finish().unwrap(), 2);
+        assert_eq!((7, entries.clone()), load_with_generation::<String, String>(&path).unwrap());
+
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN + 5] ^= 1;
+        fs::write(&path, data).unwrap();
+        let err = load::<String, String>(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_load_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snap");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1u16.to_le_bytes());
+        push_field(&mut data, &"1".to_string());
+        push_field(&mut data, &"@100".to_string());
+        data.extend_from_slice(&1u64.to_le_bytes());
+        let crc = crc32fast::hash(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let entries = load::<String, crate::ttl::Expiring<String>>(&path).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!("@100", entries[0].1.value);
+        assert_eq!(0, load_with_generation::<String, String>(&path).unwrap().0);
+        assert_eq!(None, entries[0].1.expires);
+    }
+}
@@ -0,0 +1,117 @@
+/// Per-entry time to live
+///
+/// Caches with TTL store every value as an `Expiring` with the wall clock
+/// time it expires at, so deadlines survive snapshots and WAL replays. Reads
+/// treat expired entries as missing, comparing a whole batch against one
+/// `now`, and a sweeper removes them through the normal write path so the
+/// removals are published by a flush like any other write.
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::task::JoinHandle;
+
+use crate::snapshot::Codec;
+
+/// A value and the time it expires at, `None` never expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expiring<V> {
+    pub value: V,
+    pub expires: Option<SystemTime>,
+}
+
+impl<V> Expiring<V> {
+    /// `value` expiring `ttl` from now.
+    pub fn new(value: V, ttl: Option<Duration>) -> Self {
+        Self {
+            value,
+            expires: ttl.map(|ttl| SystemTime::now() + ttl),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// The value unless it expired at `now`.
+    pub fn live(&self, now: SystemTime) -> Option<&V> {
+        (!self.is_expired(now)).then_some(&self.value)
+    }
+}
+
+/// Encoded as the expiry in milliseconds since the epoch, 0 for none, then
+/// the value.
+impl<V: Codec> Codec for Expiring<V> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let ms = self
+            .expires
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| (d.as_millis() as u64).max(1));
+        ms.encode(buf);
+        self.value.encode(buf);
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid expiry"));
+        }
+        let (ms, value) = bytes.split_at(8);
+        let ms = u64::decode(ms)?;
+        Ok(Self {
+            value: V::decode(value)?,
+            expires: (ms > 0).then(|| UNIX_EPOCH + Duration::from_millis(ms)),
+        })
+    }
+
+    /// Snapshots and WALs before version 2 hold plain values, which never
+    /// expire.
+    fn decode_version(bytes: &[u8], version: u16) -> io::Result<Self> {
+        if version < 2 {
+            return Ok(Self {
+                value: V::decode_version(bytes, version)?,
+                expires: None,
+            });
+        }
+        Self::decode(bytes)
+    }
+}
+
+/// Calls `expire` every `interval` on the blocking pool until the returned
+/// handle is aborted. `expire` queues the removal of the expired entries and
+/// returns how many it found.
+pub fn spawn_sweeper<F>(interval: Duration, expire: F) -> JoinHandle<()>
+where
+    F: Fn() -> usize + Clone + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if tokio::task::spawn_blocking(expire.clone()).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        let now = SystemTime::now();
+        for ttl in [None, Some(Duration::from_secs(60))] {
+            let value = Expiring::new("v".to_string(), ttl);
+            let mut buf = Vec::new();
+            value.encode(&mut buf);
+            let decoded = Expiring::<String>::decode(&buf).unwrap();
+            assert_eq!(decoded.value, "v");
+            assert_eq!(decoded.expires.is_some(), ttl.is_some());
+            assert!(!decoded.is_expired(now));
+        }
+        let expired = Expiring::new(1, Some(Duration::ZERO));
+        assert!(expired.is_expired(SystemTime::now()));
+        assert_eq!(expired.live(SystemTime::now()), None);
+    }
+}
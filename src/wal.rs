@@ -0,0 +1,327 @@
+/// Write-ahead log of the writes since the last snapshot
+///
+/// Layout, integers are little endian:
+///
+/// ```text
+/// magic "GBCW" | version: u16
+/// records: op: u8 [| key_len: u32 | key [| value_len: u32 | value]] | crc32: u32
+/// ```
+///
+/// `op` is `PUT`, `REMOVE` or `FLUSH`, removes carry no value and flushes no
+/// key. The crc covers the record it ends. Since version 3 a flush record
+/// follows the writes each flush published. Since version 2 the values of caches with TTL are
+/// `ttl::Expiring` values, a version 1 log is replayed with values that never
+/// expire and rewritten in the current format. A record torn by a crash ends
+/// the log and is cut off when the log is reopened.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::snapshot::{push_field, tmp_path, Codec};
+
+const MAGIC: &[u8; 4] = b"GBCW";
+const VERSION: u16 = 3;
+const HEADER_LEN: u64 = 4 + 2;
+
+const PUT: u8 = 1;
+const REMOVE: u8 = 2;
+const FLUSH: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record<K, V> {
+    Put(K, V),
+    Remove(K),
+    /// The records before it were published.
+    Flush,
+}
+
+/// When appended records are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every record.
+    Always,
+    /// On the first append once the last fsync is older than the interval.
+    Every(Duration),
+    /// Left to the OS.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// Parses `always`, `never` or an interval in milliseconds such as `100ms`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .map(|ms| FsyncPolicy::Every(Duration::from_millis(ms)))
+                .ok_or_else(|| format!("invalid fsync policy {:?}", s)),
+        }
+    }
+}
+
+pub struct Wal {
+    file: File,
+    policy: FsyncPolicy,
+    last_sync: Instant,
+    buf: Vec<u8>,
+}
+
+impl Wal {
+    /// Opens or creates the log at `path` and returns the writes it holds.
+    pub fn open<K: Codec, V: Codec>(
+        path: impl AsRef<Path>,
+        policy: FsyncPolicy,
+    ) -> io::Result<(Self, Vec<Record<K, V>>)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let (records, valid, version) = if data.is_empty() {
+            (Vec::new(), 0, VERSION)
+        } else if data.len() < HEADER_LEN as usize
+            || &data[..4] != MAGIC
+            || !(1..=VERSION).contains(&u16::decode(&data[4..6])?)
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "wal: invalid header"));
+        } else {
+            let version = u16::decode(&data[4..6])?;
+            let (records, valid) = decode(&data[HEADER_LEN as usize..], version);
+            (records, valid, version)
+        };
+
+        if version < VERSION {
+            // Appends must follow records of the same version
+            let tmp = tmp_path(path);
+            let mut wal = Self::new(File::create(&tmp)?, policy);
+            wal.truncate()?;
+            for record in &records {
+                match record {
+                    Record::Put(key, value) => wal.append_put(key, value)?,
+                    Record::Remove(key) => wal.append_remove(key)?,
+                    Record::Flush => wal.append_flush()?,
+                }
+            }
+            wal.sync()?;
+            fs::rename(&tmp, path)?;
+            return Ok((wal, records));
+        }
+
+        let mut wal = Self::new(file, policy);
+        if data.is_empty() {
+            wal.truncate()?;
+        } else {
+            // Cut off a record torn by a crash so appends follow the last good one
+            wal.file.set_len(HEADER_LEN + valid as u64)?;
+            wal.file.seek(SeekFrom::End(0))?;
+        }
+        Ok((wal, records))
+    }
+
+    fn new(file: File, policy: FsyncPolicy) -> Self {
+        Self {
+            file,
+            policy,
+            last_sync: Instant::now(),
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn append_put<K: Codec, V: Codec>(&mut self, key: &K, value: &V) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(PUT);
+        push_field(&mut self.buf, key);
+        push_field(&mut self.buf, value);
+        self.append()
+    }
+
+    pub fn append_remove<K: Codec>(&mut self, key: &K) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(REMOVE);
+        push_field(&mut self.buf, key);
+        self.append()
+    }
+
+    /// Marks the records so far as published by a flush.
+    pub fn append_flush(&mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(FLUSH);
+        self.append()
+    }
+
+    /// Size of the log in bytes.
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Drops every record, once they are covered by a snapshot.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(MAGIC)?;
+        self.file.write_all(&VERSION.to_le_bytes())?;
+        self.sync()
+    }
+
+    /// Writes the record in `buf`. A failed append is cut off again, so
+    /// the log ends with the last record appended successfully.
+    fn append(&mut self) -> io::Result<()> {
+        let crc = crc32fast::hash(&self.buf);
+        self.buf.extend_from_slice(&crc.to_le_bytes());
+        let end = self.file.stream_position()?;
+        let written = self.file.write_all(&self.buf).and_then(|()| match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Every(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        });
+        if written.is_err() {
+            let _ = self.file.set_len(end);
+            let _ = self.file.seek(SeekFrom::Start(end));
+        }
+        written
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+/// Decodes records up to the first torn or corrupt one, returns them with
+/// the length of the valid prefix.
+fn decode<K: Codec, V: Codec>(data: &[u8], version: u16) -> (Vec<Record<K, V>>, usize) {
+    let mut records = Vec::new();
+    let mut valid = 0;
+    while let Some((record, len)) = decode_record(&data[valid..], version) {
+        records.push(record);
+        valid += len;
+    }
+    (records, valid)
+}
+
+fn decode_record<K: Codec, V: Codec>(
+    data: &[u8],
+    version: u16,
+) -> Option<(Record<K, V>, usize)> {
+    let field = |at: usize| -> Option<(&[u8], usize)> {
+        let len = u32::decode(data.get(at..at + 4)?).ok()? as usize;
+        Some((data.get(at + 4..at + 4 + len)?, at + 4 + len))
+    };
+    let op = *data.first()?;
+    let (fields, end) = match op {
+        PUT => {
+            let (key, end) = field(1)?;
+            let (value, end) = field(end)?;
+            (Some((key, Some(value))), end)
+        }
+        REMOVE => {
+            let (key, end) = field(1)?;
+            (Some((key, None)), end)
+        }
+        FLUSH => (None, 1),
+        _ => return None,
+    };
+    let crc = u32::decode(data.get(end..end + 4)?).ok()?;
+    if crc32fast::hash(&data[..end]) != crc {
+        return None;
+    }
+    let record = match fields {
+        Some((key, Some(value))) => Record::Put(
+            K::decode_version(key, version).ok()?,
+            V::decode_version(value, version).ok()?,
+        ),
+        Some((key, None)) => Record::Remove(K::decode_version(key, version).ok()?),
+        None => Record::Flush,
+    };
+    Some((record, end + 4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ttl::Expiring;
+
+    #[test]
+    fn test_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.wal");
+
+        let (mut wal, records) = Wal::open::<String, String>(&path, FsyncPolicy::Always).unwrap();
+        assert!(records.is_empty());
+        wal.append_put(&"1".to_string(), &"@100".to_string()).unwrap();
+        wal.append_remove(&"2".to_string()).unwrap();
+        wal.append_flush().unwrap();
+        drop(wal);
+
+        // Torn record at the end
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[PUT, 5, 0]).unwrap();
+        drop(file);
+
+        let (mut wal, records) = Wal::open::<String, String>(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(
+            vec![
+                Record::Put("1".to_string(), "@100".to_string()),
+                Record::Remove("2".to_string()),
+                Record::Flush,
+            ],
+            records
+        );
+        wal.append_put(&"3".to_string(), &"@300".to_string()).unwrap();
+        drop(wal);
+
+        let (mut wal, records) = Wal::open::<String, String>(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(4, records.len());
+        wal.truncate().unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open::<String, String>(&path, FsyncPolicy::Never).unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn test_replay_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.wal");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1u16.to_le_bytes());
+        let mut record = vec![PUT];
+        push_field(&mut record, &"1".to_string());
+        push_field(&mut record, &"@100".to_string());
+        let crc = crc32fast::hash(&record);
+        data.extend_from_slice(&record);
+        data.extend_from_slice(&crc.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let (mut wal, records) =
+            Wal::open::<String, Expiring<String>>(&path, FsyncPolicy::Never).unwrap();
+        let value = Expiring::new("@100".to_string(), None);
+        assert_eq!(vec![Record::Put("1".to_string(), value)], records);
+        wal.append_remove(&"2".to_string()).unwrap();
+        drop(wal);
+
+        // Rewritten in the current version, so the deadlines decode
+        assert_eq!(&VERSION.to_le_bytes()[..], &fs::read(&path).unwrap()[4..6]);
+        let (_, records) =
+            Wal::open::<String, Expiring<String>>(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(
+            vec![
+                Record::Put("1".to_string(), Expiring::new("@100".to_string(), None)),
+                Record::Remove("2".to_string()),
+            ],
+            records
+        );
+    }
+}
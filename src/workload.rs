@@ -0,0 +1,815 @@
+/// Benchmark workload shared by `cache-bench` and `cache-client`
+///
+/// The writer loads keys `1..=write_iters`, then `readers` tasks read batches
+/// drawn from `distribution` while the writer rewrites every key `write_rounds` times.
+/// In `mixed` mode `writers` tasks write and the cache is flushed every
+/// `flush_interval_ms` for `duration_ms`, reader latency is reported per flush.
+/// With `target_qps` readers run open loop: batches are issued on a fixed
+/// schedule and latency is measured from the time a batch was due, so stalls
+/// also count against the batches that queued up behind them.
+///
+/// Caches are driven through `BenchReader`/`BenchWriter`, implemented for
+/// every local backend and by the gRPC client.
+use std::fmt::Write;
+use std::future::{self, Future};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinError;
+use tokio::task::yield_now;
+use tokio::time::{sleep, sleep_until};
+
+use crate::cache::{CacheReader, CacheStatus, CacheWriter};
+use crate::keygen::{Distribution, KeyGen, Latest};
+use crate::metrics::{Histogram, Metrics};
+use crate::{gbcache, gbcache2, lrcache, rwcache};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    Rw,
+    Greenblue,
+    Greenblue2,
+    LeftRight,
+}
+
+/// Errors of a run, remote caches fail in more ways than `CacheError`.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Read side of a benchmarked cache, each reader task owns one.
+pub trait BenchReader: Send + 'static {
+    fn get(&mut self, keys: &[String]) -> impl Future<Output = Result<Vec<Option<Bytes>>>> + Send;
+}
+
+/// Write side of a benchmarked cache, cloned for every writer task.
+pub trait BenchWriter: Clone + Send + Sync + 'static {
+    fn put(&self, key: String, value: Bytes) -> impl Future<Output = Result<()>> + Send;
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+
+    fn status(&self) -> impl Future<Output = Result<CacheStatus>> + Send;
+}
+
+/// Local caches answer before the future is returned, so readers that are
+/// not `Sync` such as `left_right` handles still give `Send` futures.
+impl<R> BenchReader for R
+where
+    R: CacheReader<String, Bytes> + Send + 'static,
+{
+    fn get(&mut self, keys: &[String]) -> impl Future<Output = Result<Vec<Option<Bytes>>>> + Send {
+        future::ready(Ok(CacheReader::get(self, keys)))
+    }
+}
+
+impl<W> BenchWriter for W
+where
+    W: CacheWriter<String, Bytes> + Clone + Send + Sync + 'static,
+{
+    fn put(&self, key: String, value: Bytes) -> impl Future<Output = Result<()>> + Send {
+        future::ready(CacheWriter::put(self, key, value).map_err(Into::into))
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        future::ready(CacheWriter::flush(self).map(|_| ()).map_err(Into::into))
+    }
+
+    fn status(&self) -> impl Future<Output = Result<CacheStatus>> + Send {
+        future::ready(Ok(CacheWriter::status(self)))
+    }
+}
+
+/// Batches or writes between two yields, so timers and the flusher still run
+/// when every worker thread is busy reading or writing.
+const YIELD_EVERY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Readers run `read_iters` batches while every key is rewritten
+    /// `write_rounds` times.
+    Rounds,
+    /// Writers and periodic flushes run alongside the readers for `duration_ms`.
+    Mixed,
+}
+
+macro_rules! bench_config {
+    ($($(#[doc = $doc:literal])+ $field:ident: $ty:ty = $default:expr,)+) => {
+        /// Benchmark parameters, read from a TOML file and overridden by flags.
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct BenchConfig {
+            $($(#[doc = $doc])+ pub $field: $ty,)+
+        }
+
+        impl Default for BenchConfig {
+            fn default() -> Self {
+                Self { $($field: $default,)+ }
+            }
+        }
+
+        /// Command line overrides, one `--flag` per `BenchConfig` field.
+        #[derive(Debug, Default, clap::Args)]
+        pub struct BenchArgs {
+            $($(#[doc = $doc])+ #[arg(long)] pub $field: Option<$ty>,)+
+        }
+
+        impl BenchArgs {
+            /// Overwrites the fields of `config` given on the command line.
+            pub fn apply(self, config: &mut BenchConfig) {
+                $(if let Some(v) = self.$field {
+                    config.$field = v;
+                })+
+            }
+        }
+    };
+}
+
+bench_config! {
+    /// Cache implementation under test.
+    backend: Backend = Backend::Greenblue,
+    /// How writes are scheduled while readers run.
+    mode: Mode = Mode::Rounds,
+    /// Concurrent reader tasks.
+    readers: usize = 3,
+    /// Batches read by each reader.
+    read_iters: usize = 10_000_000,
+    /// Keys per read batch.
+    batch_size: usize = 10,
+    /// Batches between two reader reports, 0 disables them.
+    read_report: usize = 200_000,
+    /// Batches slower than this count as timeouts, in microseconds.
+    read_timeout_us: u64 = 3_000,
+    /// Pause after each read batch in closed loop, in nanoseconds.
+    read_throttle_ns: u64 = 0,
+    /// Read batches per second over all readers, 0 runs closed loop.
+    target_qps: u64 = 0,
+    /// Keys written, `1..=write_iters`.
+    write_iters: usize = 5_000_000,
+    /// Writes between two flushes.
+    write_flush: usize = 5_000_000,
+    /// Pause after each write while readers run, in nanoseconds.
+    write_throttle_ns: u64 = 0,
+    /// Rewrites of every key while readers run.
+    write_rounds: usize = 3,
+    /// Pause before each rewrite, in milliseconds.
+    write_interval_ms: u64 = 5_000,
+    /// Concurrent writer tasks in mixed mode.
+    writers: usize = 1,
+    /// Length of a mixed run, in milliseconds.
+    duration_ms: u64 = 10_000,
+    /// Time between two flushes in mixed mode, in milliseconds.
+    flush_interval_ms: u64 = 1_000,
+    /// How readers pick keys.
+    distribution: Distribution = Distribution::Uniform,
+    /// Skew of the zipfian and latest distributions, larger is hotter.
+    zipf_exponent: f64 = 0.99,
+    /// Share of the keys that are hot in the hotspot distribution.
+    hot_fraction: f64 = 0.2,
+    /// Share of the reads going to the hot keys in the hotspot distribution.
+    hot_ratio: f64 = 0.8,
+    /// Share of the keys read that were never written.
+    miss_ratio: f64 = 0.0,
+    /// Seed of the reader key generators, 0 picks a random one.
+    seed: u64 = 0,
+}
+
+impl BenchConfig {
+    /// Checks the parameters that would otherwise fail in the middle of a run.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let ratio = |name: &str, v: f64| {
+            if (0.0..=1.0).contains(&v) {
+                Ok(())
+            } else {
+                Err(format!("{} must be within 0..=1, got {}", name, v))
+            }
+        };
+        ratio("hot_fraction", self.hot_fraction)?;
+        ratio("hot_ratio", self.hot_ratio)?;
+        ratio("miss_ratio", self.miss_ratio)?;
+        if self.batch_size == 0 {
+            return Err("batch_size must be above 0".to_string());
+        }
+        if self.mode == Mode::Mixed && (self.writers == 0 || self.flush_interval_ms == 0) {
+            return Err("mixed mode needs writers and flush_interval_ms above 0".to_string());
+        }
+        if self.zipf_exponent.is_nan() || self.zipf_exponent <= 0.0 {
+            return Err(format!("zipf_exponent must be positive, got {}", self.zipf_exponent));
+        }
+        Ok(())
+    }
+}
+
+/// Results of a run, serialized as the machine readable summary.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub config: BenchConfig,
+    /// Initial load, before readers start.
+    pub load: WriteSummary,
+    /// Rewrites done while readers run, one per writer in mixed mode.
+    pub rewrites: Vec<WriteSummary>,
+    pub reads: ReadSummary,
+    /// Flushes of a mixed run with the reader latency around them.
+    pub flushes: Vec<FlushReport>,
+    /// Items visible at the end of the run.
+    pub items: usize,
+    pub generation: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WriteSummary {
+    pub puts: usize,
+    pub flushes: usize,
+    pub seconds: f64,
+    pub puts_per_sec: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadSummary {
+    pub batches: u64,
+    pub keys: usize,
+    pub hits: usize,
+    /// Batches that failed, only remote caches fail reads.
+    pub errors: usize,
+    pub seconds: f64,
+    pub batches_per_sec: f64,
+    pub keys_per_sec: f64,
+    pub timeouts: usize,
+    pub success_pct: f64,
+    pub latency_us: LatencySummary,
+}
+
+/// Flush of a mixed run, `during` and `after` cover the reader batches that
+/// started while the flush ran and until the next one.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlushReport {
+    /// Since the readers started.
+    pub start_ms: f64,
+    pub duration_ms: f64,
+    /// Writes published by the flush.
+    pub pending: usize,
+    /// Items visible after the flush.
+    pub items: usize,
+    pub ok: bool,
+    pub during: LatencySummary,
+    pub after: LatencySummary,
+}
+
+/// Batch latency distribution in microseconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub p999: f64,
+    pub max: f64,
+}
+
+impl From<&Histogram> for LatencySummary {
+    fn from(h: &Histogram) -> Self {
+        let us = |d: Duration| d.as_secs_f64() * 1e6;
+        let p = h.percentiles();
+        Self {
+            count: h.count(),
+            mean: if h.count() == 0 {
+                0.0
+            } else {
+                us(h.sum()) / h.count() as f64
+            },
+            p50: us(p.p50),
+            p90: us(p.p90),
+            p99: us(p.p99),
+            p999: us(p.p999),
+            max: us(p.max),
+        }
+    }
+}
+
+fn per_sec(n: usize, elapsed: Duration) -> f64 {
+    n as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Payload written for key `n`, a few bytes like a small feature vector.
+fn value(n: usize) -> Bytes {
+    Bytes::from(format!("@{}", n))
+}
+
+/// Propagates a panic of a spawned task to the caller.
+fn joined<T>(r: std::result::Result<T, JoinError>) -> T {
+    r.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Runs the benchmark on the backend selected by `config`.
+pub async fn run(config: BenchConfig) -> Result<Summary> {
+    match config.backend {
+        Backend::Rw => {
+            let cache = Arc::new(rwcache::RwCache::default());
+            run_with(cache.clone(), |_| cache.clone(), config).await
+        }
+        Backend::Greenblue => {
+            let cache = gbcache::GreenBlueCache::with_capacity(config.write_iters);
+            let cache = Arc::new(cache);
+            run_with(cache.clone(), |_| cache.clone(), config).await
+        }
+        Backend::Greenblue2 => {
+            let cache = Arc::new(gbcache2::GreenBlueCache::default());
+            run_with(cache.clone(), |_| cache.clone(), config).await
+        }
+        Backend::LeftRight => {
+            let (w, r) = lrcache::new();
+            run_with(Arc::new(w), |_| r.clone(), config).await
+        }
+    }
+}
+
+/// State shared by the tasks of a run.
+#[derive(Default)]
+struct Shared {
+    /// Last key written, for the `latest` distribution.
+    latest: Latest,
+    /// Incremented when a flush starts and when it ends, odd while flushing.
+    phase: AtomicUsize,
+    /// Set when a mixed run is over.
+    stop: AtomicBool,
+}
+
+/// What a reader task measured.
+#[derive(Default)]
+struct ReadResult {
+    metrics: Metrics,
+    hits: usize,
+    errors: usize,
+    /// Batch latencies per flush phase.
+    phases: Vec<Histogram>,
+}
+
+impl ReadResult {
+    fn record_phase(&mut self, phase: usize, duration: Duration) {
+        if self.phases.len() <= phase {
+            self.phases.resize_with(phase + 1, Histogram::default);
+        }
+        self.phases[phase].record(duration);
+    }
+
+    fn merge(&mut self, other: &ReadResult) {
+        self.metrics.merge(&other.metrics);
+        self.hits += other.hits;
+        self.errors += other.errors;
+        if self.phases.len() < other.phases.len() {
+            self.phases.resize_with(other.phases.len(), Histogram::default);
+        }
+        for (h, o) in self.phases.iter_mut().zip(&other.phases) {
+            h.merge(o);
+        }
+    }
+}
+
+/// A flush of a mixed run, before reader latencies are attached.
+struct FlushEvent {
+    start: Duration,
+    duration: Duration,
+    pending: usize,
+    items: usize,
+    ok: bool,
+}
+
+/// Runs the benchmark with any backend, reader task `i` reads from `reader(i)`.
+///
+/// A random seed is picked when `config.seed` is 0, the summary reports the
+/// seed used so the run can be reproduced. Fails before anything runs if
+/// `config` does not pass `BenchConfig::validate`.
+pub async fn run_with<W, R>(
+    writer: W,
+    reader: impl Fn(usize) -> R,
+    mut config: BenchConfig,
+) -> Result<Summary>
+where
+    W: BenchWriter,
+    R: BenchReader,
+{
+    config.validate()?;
+    if config.seed == 0 {
+        config.seed = rand::thread_rng().gen_range(1..=u64::MAX);
+    }
+    let shared = Arc::new(Shared::default());
+    let spawn_writer = |throttle: Duration| {
+        let (w, config, shared) = (writer.clone(), config.clone(), shared.clone());
+        tokio::spawn(async move { write(&w, &config, &shared.latest, throttle).await })
+    };
+    let load = joined(spawn_writer(Duration::ZERO).await)?;
+
+    eprintln!(">>>>>>> SPAWN READERS....");
+    let start = Instant::now();
+    let ts: Vec<_> = (0..config.readers)
+        .map(|i| {
+            let keys = KeyGen::new(&config, i, shared.latest.clone());
+            let (r, config, shared) = (reader(i), config.clone(), shared.clone());
+            tokio::spawn(async move { read(r, &config, keys, &shared, i).await })
+        })
+        .collect();
+
+    let mut rewrites = Vec::new();
+    let mut events = Vec::new();
+    match config.mode {
+        Mode::Rounds => {
+            eprintln!(">>>>>>> START WRITE SCHEDULE....");
+            for _ in 0..config.write_rounds {
+                sleep(Duration::from_millis(config.write_interval_ms)).await;
+                let throttle = Duration::from_nanos(config.write_throttle_ns);
+                rewrites.push(joined(spawn_writer(throttle).await)?);
+            }
+        }
+        Mode::Mixed => {
+            eprintln!(">>>>>>> START WRITERS AND FLUSHES....");
+            let ws: Vec<_> = (0..config.writers)
+                .map(|i| {
+                    let (w, config, shared) = (writer.clone(), config.clone(), shared.clone());
+                    tokio::spawn(async move { write_until_stopped(&w, &config, &shared, i).await })
+                })
+                .collect();
+            let flusher = {
+                let (w, config, shared) = (writer.clone(), config.clone(), shared.clone());
+                tokio::spawn(async move { flush_periodically(&w, &config, &shared, start).await })
+            };
+            sleep(Duration::from_millis(config.duration_ms)).await;
+            shared.stop.store(true, Ordering::Relaxed);
+            for w in ws {
+                rewrites.push(joined(w.await)?);
+            }
+            events = joined(flusher.await)?;
+        }
+    }
+
+    let mut reads = ReadResult::default();
+    for t in ts {
+        reads.merge(&joined(t.await));
+    }
+    let elapsed = start.elapsed();
+
+    let phase = |i: usize| -> LatencySummary {
+        reads.phases.get(i).map(Into::into).unwrap_or_default()
+    };
+    let flushes = events
+        .into_iter()
+        .enumerate()
+        .map(|(k, e)| FlushReport {
+            start_ms: e.start.as_secs_f64() * 1e3,
+            duration_ms: e.duration.as_secs_f64() * 1e3,
+            pending: e.pending,
+            items: e.items,
+            ok: e.ok,
+            during: phase(2 * k + 1),
+            after: phase(2 * k + 2),
+        })
+        .collect();
+
+    let metrics = &reads.metrics;
+    let status = writer.status().await?;
+    Ok(Summary {
+        load,
+        rewrites,
+        reads: ReadSummary {
+            batches: metrics.total_latency().count(),
+            keys: metrics.count(),
+            hits: reads.hits,
+            errors: reads.errors,
+            seconds: elapsed.as_secs_f64(),
+            batches_per_sec: per_sec(metrics.total_latency().count() as usize, elapsed),
+            keys_per_sec: per_sec(metrics.count(), elapsed),
+            timeouts: metrics.timeouts(),
+            success_pct: metrics.success(),
+            latency_us: metrics.total_latency().into(),
+        },
+        flushes,
+        items: status.items(),
+        generation: status.generation,
+        config,
+    })
+}
+
+async fn write<W>(
+    cache: &W,
+    config: &BenchConfig,
+    latest: &Latest,
+    throttle: Duration,
+) -> Result<WriteSummary>
+where
+    W: BenchWriter,
+{
+    eprintln!(">>>>>>>>>>>>>>>>>>>>>> WRITING INITIATED!!");
+    let start = Instant::now();
+    let mut flushes = 0;
+    for i in 1..=config.write_iters {
+        cache.put(format!("{}", i), value(100 * i)).await?;
+        latest.store(i, Ordering::Relaxed);
+        if !throttle.is_zero() {
+            sleep(throttle).await;
+        } else if i % YIELD_EVERY == 0 {
+            yield_now().await;
+        }
+        if config.write_flush > 0 && i % config.write_flush == 0 {
+            cache.flush().await?;
+            flushes += 1;
+        }
+    }
+    cache.flush().await?;
+    flushes += 1;
+    let elapsed = start.elapsed();
+
+    eprintln!("Thread {:?} {}", std::thread::current().id(), cache.status().await?);
+    eprintln!("<<<<<<<<<<<<<<<<<<<<< WRITE DONE!!");
+
+    Ok(WriteSummary {
+        puts: config.write_iters,
+        flushes,
+        seconds: elapsed.as_secs_f64(),
+        puts_per_sec: per_sec(config.write_iters, elapsed),
+    })
+}
+
+/// Writer `writer` of a mixed run, cycles over its share of the keys until
+/// the run stops, flushes are left to `flush_periodically`.
+async fn write_until_stopped<W>(
+    cache: &W,
+    config: &BenchConfig,
+    shared: &Shared,
+    writer: usize,
+) -> Result<WriteSummary>
+where
+    W: BenchWriter,
+{
+    let start = Instant::now();
+    let throttle = Duration::from_nanos(config.write_throttle_ns);
+    let keys = config.write_iters.max(1);
+    let mut key = writer % keys;
+    let mut puts = 0;
+    while !shared.stop.load(Ordering::Relaxed) {
+        let k = key + 1;
+        cache.put(format!("{}", k), value(100 * k)).await?;
+        shared.latest.store(k, Ordering::Relaxed);
+        key = (key + config.writers) % keys;
+        puts += 1;
+        if !throttle.is_zero() {
+            sleep(throttle).await;
+        } else if puts % YIELD_EVERY == 0 {
+            yield_now().await;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    Ok(WriteSummary {
+        puts,
+        flushes: 0,
+        seconds: elapsed.as_secs_f64(),
+        puts_per_sec: per_sec(puts, elapsed),
+    })
+}
+
+/// Flushes every `flush_interval_ms` until the run stops, `start` is the
+/// time readers started.
+async fn flush_periodically<W>(
+    cache: &W,
+    config: &BenchConfig,
+    shared: &Shared,
+    start: Instant,
+) -> Result<Vec<FlushEvent>>
+where
+    W: BenchWriter,
+{
+    let mut events = Vec::new();
+    loop {
+        sleep(Duration::from_millis(config.flush_interval_ms)).await;
+        if shared.stop.load(Ordering::Relaxed) {
+            return Ok(events);
+        }
+        let pending = cache.status().await?.pending;
+        let t = Instant::now();
+        shared.phase.fetch_add(1, Ordering::Relaxed);
+        let ok = cache.flush().await.is_ok();
+        let duration = t.elapsed();
+        shared.phase.fetch_add(1, Ordering::Relaxed);
+        eprintln!(
+            "Flush {} took {:?} pending: {} ok: {}",
+            events.len(),
+            duration,
+            pending,
+            ok
+        );
+        events.push(FlushEvent {
+            start: t - start,
+            duration,
+            pending,
+            items: cache.status().await?.items(),
+            ok,
+        });
+    }
+}
+
+/// Waits until `until`, timers are only millisecond precise so the last
+/// stretch is spent yielding.
+async fn pace(until: Instant) {
+    if let Some(coarse) = until.checked_sub(Duration::from_millis(1)) {
+        if coarse > Instant::now() {
+            sleep_until(coarse.into()).await;
+        }
+    }
+    while Instant::now() < until {
+        yield_now().await;
+    }
+}
+
+/// Reads until `read_iters` batches are done, or until a mixed run stops.
+async fn read<R>(
+    mut cache: R,
+    config: &BenchConfig,
+    mut keygen: KeyGen,
+    shared: &Shared,
+    reader: usize,
+) -> ReadResult
+where
+    R: BenchReader,
+{
+    let mut result = ReadResult::default();
+    let timeout = Duration::from_micros(config.read_timeout_us);
+    let throttle = Duration::from_nanos(config.read_throttle_ns);
+    let interval = (config.target_qps > 0)
+        .then(|| Duration::from_secs_f64(config.readers as f64 / config.target_qps as f64));
+    let begin = Instant::now();
+    // Rewritten in place for every batch, reads do not allocate keys
+    let mut keys = vec![String::new(); config.batch_size];
+    for i in 1.. {
+        let done = match config.mode {
+            Mode::Rounds => i > config.read_iters,
+            Mode::Mixed => shared.stop.load(Ordering::Relaxed),
+        };
+        if done {
+            break;
+        }
+        let start = match interval {
+            Some(interval) => {
+                let due = begin + interval.mul_f64((i - 1) as f64);
+                pace(due).await;
+                due
+            }
+            None => Instant::now(),
+        };
+        let phase = shared.phase.load(Ordering::Relaxed);
+
+        for key in keys.iter_mut() {
+            key.clear();
+            write!(key, "{}", keygen.next_key()).unwrap();
+        }
+
+        let vs = match cache.get(keys.as_slice()).await {
+            Ok(vs) => vs,
+            Err(e) => {
+                if result.errors == 0 {
+                    eprintln!("Reader {} get failed: {}", reader, e);
+                }
+                result.errors += 1;
+                continue;
+            }
+        };
+        let elapsed = start.elapsed();
+        result.metrics.put(config.batch_size, elapsed, timeout);
+        if config.mode == Mode::Mixed {
+            result.record_phase(phase, elapsed);
+        }
+        result.hits += vs.iter().filter(|v| v.is_some()).count();
+        if interval.is_none() && !throttle.is_zero() {
+            sleep(throttle).await;
+        } else if i % YIELD_EVERY == 0 {
+            yield_now().await;
+        }
+        if config.read_report > 0 && i % config.read_report == 0 {
+            eprintln!(
+                "Thread {:?} Reader {} i: {} Got {:?}:{:?} {:?}",
+                std::thread::current().id(),
+                reader,
+                i,
+                keys.first(),
+                vs.first(),
+                result.metrics
+            );
+            result.metrics.reset_window();
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let mut config: BenchConfig =
+            toml::from_str("backend = \"left-right\"\nreaders = 8\n").unwrap();
+        assert_eq!(config.backend, Backend::LeftRight);
+        assert_eq!(config.readers, 8);
+        assert_eq!(config.batch_size, BenchConfig::default().batch_size);
+
+        let args = BenchArgs {
+            readers: Some(2),
+            ..Default::default()
+        };
+        args.apply(&mut config);
+        assert_eq!(config.readers, 2);
+        assert_eq!(config.backend, Backend::LeftRight);
+
+        assert!(toml::from_str::<BenchConfig>("reader = 1").is_err());
+        assert_eq!(config.validate(), Ok(()));
+        config.batch_size = 0;
+        assert!(config.validate().is_err());
+        config.batch_size = 1;
+        config.miss_ratio = 1.5;
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_run_backends() {
+        for backend in Backend::value_variants() {
+            let config = BenchConfig {
+                backend: *backend,
+                readers: 2,
+                read_iters: 50,
+                read_report: 0,
+                write_iters: 100,
+                write_flush: 30,
+                write_rounds: 1,
+                write_interval_ms: 0,
+                ..Default::default()
+            };
+            let summary = run(config).await.unwrap();
+            assert_eq!(summary.items, 100, "{:?}", backend);
+            assert_eq!(summary.load.flushes, 4);
+            assert_eq!(summary.rewrites.len(), 1);
+            assert_eq!(summary.reads.batches, 100);
+            assert_eq!(summary.reads.keys, 1000);
+            assert_eq!(summary.reads.hits, 1000);
+            assert_ne!(summary.config.seed, 0);
+            assert!(summary.flushes.is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_open_loop() {
+        let config = BenchConfig {
+            readers: 2,
+            read_iters: 100,
+            read_report: 0,
+            target_qps: 2_000,
+            write_iters: 100,
+            write_rounds: 0,
+            ..Default::default()
+        };
+        let summary = run(config).await.unwrap();
+        assert_eq!(summary.reads.batches, 200);
+        // The last batch of each reader is due 99 intervals of 1ms in
+        assert!(summary.reads.seconds >= 0.099, "{}", summary.reads.seconds);
+        assert!(summary.reads.batches_per_sec <= 2_020.0);
+
+        // Out of range settings fail the run instead of panicking a task
+        let config = BenchConfig {
+            hot_ratio: 2.0,
+            ..Default::default()
+        };
+        assert!(run(config).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_mixed() {
+        for backend in Backend::value_variants() {
+            let config = BenchConfig {
+                backend: *backend,
+                mode: Mode::Mixed,
+                readers: 2,
+                read_report: 0,
+                write_iters: 100,
+                writers: 2,
+                duration_ms: 300,
+                flush_interval_ms: 50,
+                ..Default::default()
+            };
+            let summary = run(config).await.unwrap();
+            assert_eq!(summary.items, 100, "{:?}", backend);
+            assert_eq!(summary.rewrites.len(), 2);
+            assert!(summary.rewrites.iter().all(|w| w.puts > 0));
+            assert!(!summary.flushes.is_empty());
+            let batches: u64 = summary
+                .flushes
+                .iter()
+                .map(|f| f.during.count + f.after.count)
+                .sum();
+            assert!(batches > 0 && batches <= summary.reads.batches);
+        }
+    }
+}